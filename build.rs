@@ -1,10 +1,30 @@
+use std::env;
+use std::path::PathBuf;
 
+const FFMPEG_LIBRARIES: [&str; 3] = ["avformat", "avcodec", "avutil"];
 
+/// links the prebuilt windows libraries in `libs`, when `FFMPEG_DIR` is set
+/// `convert.c` is compiled against that FFmpeg build instead
 fn main() {
-    println!("cargo:rustc-link-search=native=./libs");
-    println!("cargo:rustc-link-lib=static=convert");
-    println!("cargo:rustc-link-lib=static=avformat");
-    println!("cargo:rustc-link-lib=static=avcodec");
-    println!("cargo:rustc-link-lib=static=avutil");
-}
+    println!("cargo:rerun-if-changed=convert.c");
+    println!("cargo:rerun-if-env-changed=FFMPEG_DIR");
 
+    match env::var_os("FFMPEG_DIR") {
+        Some(dir) => {
+            let dir = PathBuf::from(dir);
+            cc::Build::new()
+                .file("convert.c")
+                .include(dir.join("include"))
+                .compile("convert");
+            println!("cargo:rustc-link-search=native={}", dir.join("lib").display());
+        }
+        None => {
+            println!("cargo:rustc-link-search=native=./libs");
+            println!("cargo:rustc-link-lib=static=convert");
+        }
+    }
+    // FFmpeg has to come after libconvert on the link line
+    for name in FFMPEG_LIBRARIES {
+        println!("cargo:rustc-link-lib=static={}", name);
+    }
+}
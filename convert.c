@@ -1,5 +1,8 @@
-#include <FFmpeg-n6.1/libavformat/avformat.h>
-#include <FFmpeg-n6.1/libavcodec/codec_par.h>
+#include <libavformat/avformat.h>
+#include <libavcodec/codec_par.h>
+#include <libavcodec/avcodec.h>
+#include <stdlib.h>
+#include <string.h>
 
 int convert_video_from_mpeg_to_mp4(char *input_file, char *output_file) {
     AVFormatContext *inFormatCtx = NULL, *outFormatCtx = NULL;
@@ -42,4 +45,351 @@ int convert_video_from_mpeg_to_mp4(char *input_file, char *output_file) {
     }
     avformat_free_context(outFormatCtx);
     return 0;
-}
\ No newline at end of file
+}
+typedef struct {
+    AVFormatContext *ctx;
+    /* output stream of every input stream */
+    int *stream_map;
+    /* WebVTT streams are decoded and encoded again as mov_text */
+    AVCodecContext **decoders;
+    AVCodecContext **encoders;
+    /* subtracted from the timestamps, so every track starts with the video */
+    int64_t offset;
+    AVPacket *pkt;
+    int has_packet;
+} MuxInput;
+
+static int open_subtitle_conversion(AVStream *inStream, AVStream *outStream, AVCodecContext **decoder, AVCodecContext **encoder) {
+    const AVCodec *decoderCodec = avcodec_find_decoder(AV_CODEC_ID_WEBVTT);
+    const AVCodec *encoderCodec = avcodec_find_encoder(AV_CODEC_ID_MOV_TEXT);
+    if (!decoderCodec || !encoderCodec) {
+        return -1;
+    }
+    *decoder = avcodec_alloc_context3(decoderCodec);
+    avcodec_parameters_to_context(*decoder, inStream->codecpar);
+    (*decoder)->pkt_timebase = inStream->time_base;
+    if (avcodec_open2(*decoder, decoderCodec, NULL) < 0) {
+        return -1;
+    }
+    *encoder = avcodec_alloc_context3(encoderCodec);
+    (*encoder)->time_base = inStream->time_base;
+    if ((*decoder)->subtitle_header) {
+        (*encoder)->subtitle_header = av_mallocz((*decoder)->subtitle_header_size + 1);
+        memcpy((*encoder)->subtitle_header, (*decoder)->subtitle_header, (*decoder)->subtitle_header_size);
+        (*encoder)->subtitle_header_size = (*decoder)->subtitle_header_size;
+    }
+    if (avcodec_open2(*encoder, encoderCodec, NULL) < 0) {
+        return -1;
+    }
+    avcodec_parameters_from_context(outStream->codecpar, *encoder);
+    outStream->time_base = (*encoder)->time_base;
+    return 0;
+}
+
+static void read_next(MuxInput *input) {
+    input->has_packet = av_read_frame(input->ctx, input->pkt) >= 0;
+}
+
+/* timestamp of the waiting packet in AV_TIME_BASE units */
+static int64_t next_timestamp(MuxInput *input) {
+    AVPacket *pkt = input->pkt;
+    int64_t timestamp = pkt->dts != AV_NOPTS_VALUE ? pkt->dts : pkt->pts;
+    if (timestamp == AV_NOPTS_VALUE) {
+        return INT64_MIN;
+    }
+    AVRational timeBase = input->ctx->streams[pkt->stream_index]->time_base;
+    return av_rescale_q(timestamp, timeBase, AV_TIME_BASE_Q) - input->offset;
+}
+
+static void write_packet(MuxInput *input, AVFormatContext *outFormatCtx) {
+    AVPacket *pkt = input->pkt;
+    int index = pkt->stream_index;
+    AVStream *inStream = input->ctx->streams[index];
+    AVStream *outStream = outFormatCtx->streams[input->stream_map[index]];
+    int64_t offset = av_rescale_q(input->offset, AV_TIME_BASE_Q, inStream->time_base);
+    if (pkt->pts != AV_NOPTS_VALUE) {
+        pkt->pts -= offset;
+    }
+    if (pkt->dts != AV_NOPTS_VALUE) {
+        pkt->dts -= offset;
+    }
+
+    if (input->decoders[index]) {
+        AVSubtitle subtitle;
+        int has_subtitle = 0;
+        if (avcodec_decode_subtitle2(input->decoders[index], &subtitle, &has_subtitle, pkt) >= 0 && has_subtitle) {
+            uint8_t buffer[65536];
+            int size = avcodec_encode_subtitle(input->encoders[index], buffer, sizeof(buffer), &subtitle);
+            if (size > 0) {
+                AVPacket *outPkt = av_packet_alloc();
+                av_new_packet(outPkt, size);
+                memcpy(outPkt->data, buffer, size);
+                outPkt->stream_index = outStream->index;
+                outPkt->pts = av_rescale_q(pkt->pts, inStream->time_base, outStream->time_base);
+                outPkt->dts = outPkt->pts;
+                outPkt->duration = av_rescale_q(pkt->duration, inStream->time_base, outStream->time_base);
+                av_interleaved_write_frame(outFormatCtx, outPkt);
+                av_packet_free(&outPkt);
+            }
+            avsubtitle_free(&subtitle);
+        }
+        av_packet_unref(pkt);
+        return;
+    }
+
+    pkt->stream_index = outStream->index;
+    av_packet_rescale_ts(pkt, inStream->time_base, outStream->time_base);
+    pkt->pos = -1;
+    av_interleaved_write_frame(outFormatCtx, pkt);
+}
+
+/* copies the streams of the video and of every track into one output, the
+   tracks are extra audio or subtitles tagged with their language. WebVTT is
+   converted to mov_text since mp4 cannot hold it as it is */
+int mux_tracks(char *video_file, char **track_files, char **languages, int track_count, char *output_file) {
+    int input_count = track_count + 1;
+    int result = -1;
+    MuxInput *inputs = calloc(input_count, sizeof(MuxInput));
+    AVFormatContext *outFormatCtx = NULL;
+    avformat_alloc_output_context2(&outFormatCtx, NULL, NULL, output_file);
+    if (!inputs || !outFormatCtx) {
+        goto end;
+    }
+
+    for (int i = 0; i < input_count; i++) {
+        MuxInput *input = &inputs[i];
+        char *file = i == 0 ? video_file : track_files[i - 1];
+        if (avformat_open_input(&input->ctx, file, NULL, NULL) < 0) {
+            goto end;
+        }
+        if (avformat_find_stream_info(input->ctx, NULL) < 0) {
+            goto end;
+        }
+        int stream_count = input->ctx->nb_streams;
+        input->stream_map = calloc(stream_count, sizeof(int));
+        input->decoders = calloc(stream_count, sizeof(AVCodecContext *));
+        input->encoders = calloc(stream_count, sizeof(AVCodecContext *));
+        input->pkt = av_packet_alloc();
+        int is_subtitle = 0;
+        for (int s = 0; s < stream_count; s++) {
+            AVStream *inStream = input->ctx->streams[s];
+            AVStream *outStream = avformat_new_stream(outFormatCtx, NULL);
+            input->stream_map[s] = outStream->index;
+            if (inStream->codecpar->codec_id == AV_CODEC_ID_WEBVTT) {
+                is_subtitle = 1;
+                if (open_subtitle_conversion(inStream, outStream, &input->decoders[s], &input->encoders[s]) < 0) {
+                    goto end;
+                }
+            } else {
+                avcodec_parameters_copy(outStream->codecpar, inStream->codecpar);
+                outStream->codecpar->codec_tag = 0;
+            }
+            if (i > 0 && languages[i - 1]) {
+                av_dict_set(&outStream->metadata, "language", languages[i - 1], 0);
+            }
+        }
+        /* WebVTT cues start at zero, mpeg-ts tracks share the clock of the video */
+        int64_t start_time = inputs[0].ctx->start_time;
+        if (!is_subtitle && start_time != AV_NOPTS_VALUE) {
+            input->offset = start_time;
+        }
+    }
+
+    if (!(outFormatCtx->oformat->flags & AVFMT_NOFILE)) {
+        if (avio_open(&outFormatCtx->pb, output_file, AVIO_FLAG_WRITE) < 0) {
+            goto end;
+        }
+    }
+    if (avformat_write_header(outFormatCtx, NULL) < 0) {
+        goto end;
+    }
+
+    for (int i = 0; i < input_count; i++) {
+        read_next(&inputs[i]);
+    }
+    while (1) {
+        MuxInput *earliest = NULL;
+        for (int i = 0; i < input_count; i++) {
+            if (inputs[i].has_packet && (!earliest || next_timestamp(&inputs[i]) < next_timestamp(earliest))) {
+                earliest = &inputs[i];
+            }
+        }
+        if (!earliest) {
+            break;
+        }
+        write_packet(earliest, outFormatCtx);
+        read_next(earliest);
+    }
+    av_write_trailer(outFormatCtx);
+    result = 0;
+
+end:
+    for (int i = 0; inputs && i < input_count; i++) {
+        MuxInput *input = &inputs[i];
+        int stream_count = input->ctx ? input->ctx->nb_streams : 0;
+        for (int s = 0; input->decoders && s < stream_count; s++) {
+            avcodec_free_context(&input->decoders[s]);
+            avcodec_free_context(&input->encoders[s]);
+        }
+        free(input->stream_map);
+        free(input->decoders);
+        free(input->encoders);
+        av_packet_free(&input->pkt);
+        avformat_close_input(&input->ctx);
+    }
+    free(inputs);
+    if (outFormatCtx && !(outFormatCtx->oformat->flags & AVFMT_NOFILE)) {
+        avio_closep(&outFormatCtx->pb);
+    }
+    avformat_free_context(outFormatCtx);
+    return result;
+}
+
+/* copies the first audio stream into an m4a file, the ADTS headers of aac in
+   mpeg-ts are converted by the muxer */
+int extract_audio(char *input_file, char *output_file, char *title) {
+    int result = -1;
+    AVFormatContext *inFormatCtx = NULL, *outFormatCtx = NULL;
+    AVPacket *pkt = av_packet_alloc();
+    if (avformat_open_input(&inFormatCtx, input_file, NULL, NULL) < 0) {
+        goto end;
+    }
+    if (avformat_find_stream_info(inFormatCtx, NULL) < 0) {
+        goto end;
+    }
+    int audio_index = av_find_best_stream(inFormatCtx, AVMEDIA_TYPE_AUDIO, -1, -1, NULL, 0);
+    if (audio_index < 0) {
+        goto end;
+    }
+    avformat_alloc_output_context2(&outFormatCtx, NULL, "ipod", output_file);
+    if (!outFormatCtx) {
+        goto end;
+    }
+    AVStream *inStream = inFormatCtx->streams[audio_index];
+    AVStream *outStream = avformat_new_stream(outFormatCtx, NULL);
+    avcodec_parameters_copy(outStream->codecpar, inStream->codecpar);
+    outStream->codecpar->codec_tag = 0;
+    av_dict_set(&outFormatCtx->metadata, "title", title, 0);
+
+    if (avio_open(&outFormatCtx->pb, output_file, AVIO_FLAG_WRITE) < 0) {
+        goto end;
+    }
+    if (avformat_write_header(outFormatCtx, NULL) < 0) {
+        goto end;
+    }
+    /* mpeg-ts timestamps rarely start at zero */
+    int64_t start = inStream->start_time != AV_NOPTS_VALUE ? inStream->start_time : 0;
+    while (av_read_frame(inFormatCtx, pkt) >= 0) {
+        if (pkt->stream_index != audio_index) {
+            av_packet_unref(pkt);
+            continue;
+        }
+        if (pkt->pts != AV_NOPTS_VALUE) {
+            pkt->pts -= start;
+        }
+        if (pkt->dts != AV_NOPTS_VALUE) {
+            pkt->dts -= start;
+        }
+        pkt->stream_index = 0;
+        av_packet_rescale_ts(pkt, inStream->time_base, outStream->time_base);
+        pkt->pos = -1;
+        av_interleaved_write_frame(outFormatCtx, pkt);
+    }
+    av_write_trailer(outFormatCtx);
+    result = 0;
+
+end:
+    av_packet_free(&pkt);
+    avformat_close_input(&inFormatCtx);
+    if (outFormatCtx) {
+        avio_closep(&outFormatCtx->pb);
+    }
+    avformat_free_context(outFormatCtx);
+    return result;
+}
+
+/* media time of a packet in AV_TIME_BASE units from the start of the file */
+static int64_t packet_time(AVFormatContext *ctx, AVPacket *pkt) {
+    int64_t timestamp = pkt->pts != AV_NOPTS_VALUE ? pkt->pts : pkt->dts;
+    if (timestamp == AV_NOPTS_VALUE) {
+        return AV_NOPTS_VALUE;
+    }
+    int64_t start_time = ctx->start_time != AV_NOPTS_VALUE ? ctx->start_time : 0;
+    return av_rescale_q(timestamp, ctx->streams[pkt->stream_index]->time_base, AV_TIME_BASE_Q) - start_time;
+}
+
+/* copies what lies between start and end seconds of an mpeg-ts file into
+   another one without re-encoding. The cut starts at the last video keyframe
+   before start so the video stays decodable, the seconds it starts at are
+   stored in cut_start */
+int trim_mpeg(char *input_file, char *output_file, double start, double end, double *cut_start) {
+    int result = -1;
+    AVFormatContext *inFormatCtx = NULL, *outFormatCtx = NULL;
+    AVPacket *pkt = av_packet_alloc();
+    int64_t start_time = (int64_t)(start * AV_TIME_BASE);
+    int64_t end_time = (int64_t)(end * AV_TIME_BASE);
+
+    /* first pass finds the keyframe, the file only holds the segments around the section */
+    if (avformat_open_input(&inFormatCtx, input_file, NULL, NULL) < 0) {
+        goto end;
+    }
+    if (avformat_find_stream_info(inFormatCtx, NULL) < 0) {
+        goto end;
+    }
+    int video_index = av_find_best_stream(inFormatCtx, AVMEDIA_TYPE_VIDEO, -1, -1, NULL, 0);
+    int64_t cut = video_index < 0 ? start_time : 0;
+    while (video_index >= 0 && av_read_frame(inFormatCtx, pkt) >= 0) {
+        int64_t time = packet_time(inFormatCtx, pkt);
+        if (pkt->stream_index == video_index && (pkt->flags & AV_PKT_FLAG_KEY) && time != AV_NOPTS_VALUE && time <= start_time) {
+            cut = time;
+        }
+        av_packet_unref(pkt);
+    }
+    avformat_close_input(&inFormatCtx);
+
+    if (avformat_open_input(&inFormatCtx, input_file, NULL, NULL) < 0) {
+        goto end;
+    }
+    if (avformat_find_stream_info(inFormatCtx, NULL) < 0) {
+        goto end;
+    }
+    avformat_alloc_output_context2(&outFormatCtx, NULL, "mpegts", output_file);
+    if (!outFormatCtx) {
+        goto end;
+    }
+    for (int i = 0; i < inFormatCtx->nb_streams; i++) {
+        AVStream *outStream = avformat_new_stream(outFormatCtx, NULL);
+        avcodec_parameters_copy(outStream->codecpar, inFormatCtx->streams[i]->codecpar);
+        outStream->codecpar->codec_tag = 0;
+    }
+    if (avio_open(&outFormatCtx->pb, output_file, AVIO_FLAG_WRITE) < 0) {
+        goto end;
+    }
+    if (avformat_write_header(outFormatCtx, NULL) < 0) {
+        goto end;
+    }
+    while (av_read_frame(inFormatCtx, pkt) >= 0) {
+        int64_t time = packet_time(inFormatCtx, pkt);
+        if (time == AV_NOPTS_VALUE || time < cut || time >= end_time) {
+            av_packet_unref(pkt);
+            continue;
+        }
+        AVStream *inStream = inFormatCtx->streams[pkt->stream_index];
+        AVStream *outStream = outFormatCtx->streams[pkt->stream_index];
+        av_packet_rescale_ts(pkt, inStream->time_base, outStream->time_base);
+        pkt->pos = -1;
+        av_interleaved_write_frame(outFormatCtx, pkt);
+    }
+    av_write_trailer(outFormatCtx);
+    *cut_start = (double)cut / AV_TIME_BASE;
+    result = 0;
+
+end:
+    av_packet_free(&pkt);
+    avformat_close_input(&inFormatCtx);
+    if (outFormatCtx) {
+        avio_closep(&outFormatCtx->pb);
+    }
+    avformat_free_context(outFormatCtx);
+    return result;
+}
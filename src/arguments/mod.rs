@@ -1,4 +1,11 @@
-use clap::Parser;
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use reqwest::header::{HeaderName, HeaderValue};
+use std::net::SocketAddr;
+use tracing::error;
+
+use crate::http;
+use crate::quota;
+use crate::video::{self, ConcurrencyMode, Languages, Section};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -6,23 +13,170 @@ pub struct KavimoArgs {
     /// path of a text file including links
     #[arg(long)]
     pub file: Option<String>,
-    /// set timer for downloads (e.g. --timer 02:00:00-08:00:00)
-    #[arg(long)]
-    pub timer: Option<String>
+    /// set timer for downloads (e.g. --timer 02:00:00-08:00:00 or
+    /// --timer "Mon-Fri 01:00-07:00, Sat,Sun 00:00-12:00")
+    #[arg(long, global = true)]
+    pub timer: Option<String>,
+    /// read the timer windows from a file, one or more per line
+    #[arg(long, global = true, conflicts_with = "timer")]
+    pub timer_file: Option<String>,
+    /// timezone of the timer windows instead of the system one (e.g. --timezone Asia/Tehran)
+    #[arg(long, global = true)]
+    pub timezone: Option<String>,
+    /// only start a video if it is expected to finish before the timer window
+    /// closes, otherwise a smaller one that fits is downloaded first
+    #[arg(long, global = true)]
+    pub whole_videos: bool,
+    /// pause downloads once this much was transferred today (e.g. --daily-quota 5GB)
+    #[arg(long, global = true, value_parser = quota::parse_size)]
+    pub daily_quota: Option<u64>,
+    /// pause downloads once this much was transferred this month
+    #[arg(long, global = true, value_parser = quota::parse_size)]
+    pub monthly_quota: Option<u64>,
+    /// extra request header, can be repeated (e.g. --header "Authorization: Bearer abc")
+    #[arg(long, global = true, value_parser = http::parse_header)]
+    pub header: Vec<(HeaderName, HeaderValue)>,
+    /// user agent of every request instead of the built in firefox one
+    #[arg(long, global = true)]
+    pub user_agent: Option<String>,
+    /// referer of every request, the iframe url of each video by default
+    #[arg(long, global = true)]
+    pub referer: Option<String>,
+    /// netscape cookies.txt whose cookies are sent with the requests
+    #[arg(long, global = true)]
+    pub cookies: Option<String>,
+    /// parts of a video downloaded at the same time, auto adapts it to the
+    /// throughput and errors of the host
+    #[arg(long, global = true, default_value = "10", value_parser = video::parse_concurrency)]
+    pub concurrency: ConcurrencyMode,
+    /// try segments on another host when theirs fails, can be repeated and
+    /// the rules are tried in order (e.g. --mirror cdn1.example=>cdn2.example)
+    #[arg(long, global = true, value_parser = video::parse_mirror)]
+    pub mirror: Vec<(String, String)>,
+    /// audio renditions downloaded next to the video, by language or name
+    /// (e.g. --audio-lang fa,en), all, none or default
+    #[arg(long, global = true, default_value = "default", value_parser = video::parse_languages)]
+    pub audio_lang: Languages,
+    /// subtitle renditions downloaded next to the video, none by default
+    /// (e.g. --subtitle-lang fa or --subtitle-lang all)
+    #[arg(long, global = true, default_value = "none", value_parser = video::parse_languages)]
+    pub subtitle_lang: Languages,
+    /// keep subtitles as .vtt files next to the video instead of muxing them
+    #[arg(long, global = true)]
+    pub subtitle_sidecar: bool,
+    /// save only the audio as an m4a file, from a separate audio track when
+    /// there is one or else from the smallest quality
+    #[arg(long, global = true, conflicts_with_all = ["subtitle_lang", "subtitle_sidecar"])]
+    pub audio_only: bool,
+    /// download only this part of every video, cut at the keyframe before
+    /// its start (e.g. --section 00:10:00-00:25:30)
+    #[arg(long, global = true, value_parser = video::parse_section)]
+    pub section: Option<Section>,
+    /// connect to addr for host instead of resolving it, can be repeated
+    /// (e.g. --resolve stream.biomaze.ir:443:10.0.0.5)
+    #[arg(long, global = true, value_parser = http::parse_resolve)]
+    pub resolve: Vec<(String, SocketAddr)>,
+    /// resolve hosts with a DNS-over-HTTPS json api (e.g. --doh https://cloudflare-dns.com/dns-query)
+    #[arg(long, global = true)]
+    pub doh: Option<String>,
+    /// http, https or socks5 proxy (e.g. --proxy socks5://127.0.0.1:1080),
+    /// without it HTTP_PROXY, HTTPS_PROXY and NO_PROXY are honored
+    #[arg(long, global = true)]
+    pub proxy: Option<String>,
+    /// which requests go through the proxy, api covers embed, playlist and key requests
+    #[arg(long, value_enum, global = true, default_value_t = ProxyScope::All)]
+    pub proxy_scope: ProxyScope,
+    /// idle connections kept open per host, unlimited by default
+    #[arg(long, global = true)]
+    pub pool_size: Option<usize>,
+    /// seconds an idle connection is kept open
+    #[arg(long, global = true, default_value_t = 90)]
+    pub pool_idle_timeout: u64,
+    /// seconds between tcp keepalive probes, off by default
+    #[arg(long, global = true)]
+    pub tcp_keepalive: Option<u64>,
+    /// http/2 usage, auto negotiates it with the server
+    #[arg(long, value_enum, global = true, default_value_t = Http2Mode::Auto)]
+    pub http2: Http2Mode,
+    /// format of progress output, json prints one event per line on stdout
+    #[arg(long, value_enum, global = true, default_value_t = ProgressFormat::Human)]
+    pub progress: ProgressFormat,
+    /// show more diagnostics, -vv for trace output
+    #[arg(short, long, action = ArgAction::Count, global = true, conflicts_with = "quiet")]
+    pub verbose: u8,
+    /// show only warnings, -qq for errors only
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    pub quiet: u8,
+    /// also write logs to this file, rotated daily (e.g. --log-file logs/kavimo.log)
+    #[arg(long, global = true)]
+    pub log_file: Option<String>,
+    /// number of videos downloaded at the same time in batch, watch and serve modes
+    #[arg(long, global = true, default_value_t = 1)]
+    pub parallel: usize,
+    #[command(subcommand)]
+    pub command: Option<KavimoCommand>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ProgressFormat {
+    Human,
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ProxyScope {
+    All,
+    Segments,
+    Api,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Http2Mode {
+    Auto,
+    Off,
+    /// http/2 without negotiation, only for servers known to support it
+    Always,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum KavimoCommand {
+    /// watch a directory and download every batch file dropped into it
+    Watch {
+        /// directory to watch, processed files are moved to done/ or failed/
+        dir: String,
+        /// seconds between two scans of the directory
+        #[arg(long, default_value_t = 10)]
+        interval: u64,
+    },
+    /// serve a local HTTP API for queueing and monitoring downloads
+    Serve {
+        /// port to listen on, the API is only bound to 127.0.0.1
+        #[arg(long, default_value_t = 8420)]
+        port: u16,
+    },
 }
 
 
 
 impl KavimoArgs {
     pub fn validate(&self) -> bool {
-        if self.timer.is_some() {
-            if self.file.is_none() {
-                println!("--timer is only valid if --file is specified");
-                return false;
-            }
+        let has_timer = self.timer.is_some() || self.timer_file.is_some();
+        let has_quota = self.daily_quota.is_some() || self.monthly_quota.is_some();
+        if self.timezone.is_some() && !has_timer && !has_quota {
+            error!("--timezone requires a timer or a quota");
+            return false;
+        }
+
+        if self.whole_videos && !has_timer {
+            error!("--whole-videos requires --timer or --timer-file");
+            return false;
+        }
+
+        if self.file.is_some() && self.command.is_some() {
+            error!("--file cannot be used together with watch or serve mode");
+            return false;
         }
 
         true
     }
 }
-
@@ -0,0 +1,304 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use super::run_job;
+use crate::timer::{Schedule, TimedDownload as _};
+use crate::video::Video;
+
+struct Pending {
+    /// tells the video apart once the queue was unlocked for probing
+    id: usize,
+    video: Video,
+    size: Probe,
+}
+
+/// advertised size of a waiting video
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Probe {
+    NotProbed,
+    /// another slot is fetching the size
+    Probing,
+    /// the size could not be fetched
+    Failed,
+    Size(usize),
+}
+
+/// how long a slot waits for the probes of other slots before looking again
+const PROBE_WAIT: Duration = Duration::from_millis(500);
+
+/// bytes and seconds of the videos finished so far, a single slot is
+/// measured so the estimate already accounts for `--parallel`
+#[derive(Default)]
+struct Throughput {
+    bytes: usize,
+    seconds: f64,
+}
+
+impl Throughput {
+    fn record(&mut self, bytes: usize, elapsed: Duration) {
+        self.bytes += bytes;
+        self.seconds += elapsed.as_secs_f64();
+    }
+
+    /// expected seconds to download `size` bytes, unknown until the first
+    /// video has finished
+    fn estimate(&self, size: usize) -> Option<f64> {
+        if self.bytes == 0 || self.seconds <= 0.0 {
+            return None;
+        }
+        Some(size as f64 * self.seconds / self.bytes as f64)
+    }
+}
+
+enum Picked {
+    Video(Video),
+    /// nothing fits the seconds left of the window
+    Deferred(u32),
+    Empty,
+}
+
+#[derive(Debug, PartialEq)]
+enum Choice {
+    Start(usize),
+    /// the size of the video is needed before going further
+    Probe(usize),
+    /// a video another slot is probing may still fit
+    Wait,
+    Defer,
+}
+
+/// every slot takes the first waiting video expected to finish before the
+/// timer window closes and sleeps until the next window when none does, so
+/// videos are not split across several windows
+pub(super) async fn download_fitting(
+    videos: Vec<Video>,
+    timer: &Schedule,
+    parallel: usize,
+    cancel: &CancellationToken,
+) -> Vec<Option<bool>> {
+    let pending = videos
+        .into_iter()
+        .enumerate()
+        .map(|(id, video)| Pending {
+            id,
+            video,
+            size: Probe::NotProbed,
+        })
+        .collect();
+    let queue = tokio::sync::Mutex::new(pending);
+    let throughput = Mutex::new(Throughput::default());
+    let time_range = Some(timer.clone());
+
+    let slots: Vec<_> = (0..parallel.max(1))
+        .map(|_| run_slot(&queue, &time_range, &throughput, cancel))
+        .collect();
+    let mut results: Vec<Option<bool>> = futures::future::join_all(slots)
+        .await
+        .into_iter()
+        .flatten()
+        .collect();
+
+    // slots only leave videos behind when cancelled
+    results.extend(queue.into_inner().iter().map(|_| None));
+    results
+}
+
+async fn run_slot(
+    queue: &tokio::sync::Mutex<Vec<Pending>>,
+    time_range: &Option<Schedule>,
+    throughput: &Mutex<Throughput>,
+    cancel: &CancellationToken,
+) -> Vec<Option<bool>> {
+    let mut results = Vec::new();
+    let Some(timer) = time_range else {
+        return results;
+    };
+    loop {
+        let picked = tokio::select! {
+            _ = cancel.cancelled() => break,
+            picked = async {
+                time_range.wait_until_in_range().await;
+                pick(queue, timer, throughput).await
+            } => picked,
+        };
+        match picked {
+            Picked::Video(video) => {
+                let started = Instant::now();
+                let result = run_job(video.clone(), time_range, cancel).await;
+                if result == Some(true) {
+                    let bytes = video.progress().transferred();
+                    throughput.lock().unwrap().record(bytes, started.elapsed());
+                }
+                results.push(result);
+            }
+            Picked::Deferred(left) => {
+                let wait = Duration::from_secs(left as u64 + 1);
+                tokio::select! {
+                    _ = cancel.cancelled() => break,
+                    _ = tokio::time::sleep(wait) => (),
+                }
+            }
+            Picked::Empty => break,
+        }
+    }
+    results
+}
+
+/// the queue is only locked to look at it, sizes are probed without holding
+/// it so the other slots are not kept waiting on the network
+async fn pick(
+    queue: &tokio::sync::Mutex<Vec<Pending>>,
+    timer: &Schedule,
+    throughput: &Mutex<Throughput>,
+) -> Picked {
+    loop {
+        let probe = {
+            let mut queue = queue.lock().await;
+            if queue.is_empty() {
+                return Picked::Empty;
+            }
+            let Some((left, length)) = timer.window_left() else {
+                return Picked::Deferred(0);
+            };
+            let sizes: Vec<Probe> = queue.iter().map(|x| x.size).collect();
+            match choose(&sizes, &throughput.lock().unwrap(), left, length) {
+                Choice::Start(index) => return Picked::Video(queue.remove(index).video),
+                Choice::Defer => {
+                    info!(
+                        "None of the {} waiting videos fits in the {}s left of the window",
+                        queue.len(),
+                        left
+                    );
+                    return Picked::Deferred(left);
+                }
+                Choice::Wait => None,
+                Choice::Probe(index) => {
+                    // marked before unlocking so no other slot probes it too
+                    queue[index].size = Probe::Probing;
+                    Some((queue[index].id, queue[index].video.clone()))
+                }
+            }
+        };
+        let Some((id, video)) = probe else {
+            tokio::time::sleep(PROBE_WAIT).await;
+            continue;
+        };
+        let size = match video.advertised_size().await {
+            Ok(size) => Probe::Size(size),
+            Err(err) => {
+                debug!("Cannot fetch size of video: {}", err);
+                Probe::Failed
+            }
+        };
+        // other slots may have taken videos in front of it meanwhile
+        let mut queue = queue.lock().await;
+        if let Some(pending) = queue.iter_mut().find(|x| x.id == id) {
+            pending.size = size;
+        }
+    }
+}
+
+/// the first video expected to finish in the `left` seconds of the window,
+/// or one longer than the whole window when nothing else fits
+fn choose(sizes: &[Probe], throughput: &Throughput, left: u32, length: u32) -> Choice {
+    let mut too_long = None;
+    let mut is_probing = false;
+    for (index, size) in sizes.iter().enumerate() {
+        let estimate = match size {
+            Probe::NotProbed => return Choice::Probe(index),
+            Probe::Probing => {
+                is_probing = true;
+                continue;
+            }
+            Probe::Failed => None,
+            Probe::Size(size) => throughput.estimate(*size),
+        };
+        match estimate {
+            // without an estimate the download itself finds out
+            None => return Choice::Start(index),
+            Some(seconds) if seconds <= left as f64 => return Choice::Start(index),
+            Some(seconds) if seconds > length as f64 => {
+                too_long.get_or_insert(index);
+            }
+            Some(seconds) => {
+                debug!("Video needs about {:.0}s, deferred", seconds);
+            }
+        }
+    }
+
+    // a video longer than the whole window is split anyway, so it only
+    // waits for the ones that fit
+    match too_long {
+        _ if is_probing => Choice::Wait,
+        Some(index) => {
+            warn!("Starting a video expected to take longer than the whole timer window");
+            Choice::Start(index)
+        }
+        None => Choice::Defer,
+    }
+}
+
+#[cfg(test)]
+mod fit_tests {
+    use super::*;
+
+    #[test]
+    fn throughput() {
+        let mut throughput = Throughput::default();
+        assert_eq!(None, throughput.estimate(1000));
+
+        throughput.record(1000, Duration::from_secs(10));
+        throughput.record(3000, Duration::from_secs(10));
+        assert_eq!(Some(50.0), throughput.estimate(10_000));
+
+        // a video that was resumed completely transferred nothing
+        throughput.record(0, Duration::ZERO);
+        assert_eq!(Some(50.0), throughput.estimate(10_000));
+    }
+
+    #[test]
+    fn choice() {
+        let mut throughput = Throughput::default();
+        let (left, length) = (100, 1000);
+
+        // nothing is known before the first video finished
+        assert_eq!(
+            Choice::Probe(0),
+            choose(&[Probe::NotProbed], &throughput, left, length)
+        );
+        assert_eq!(
+            Choice::Start(0),
+            choose(&[Probe::Size(500)], &throughput, left, length)
+        );
+
+        // 10 bytes a second
+        throughput.record(1000, Duration::from_secs(100));
+        let sizes = [Probe::Size(5000), Probe::Size(500), Probe::Size(20_000)];
+        assert_eq!(Choice::Start(1), choose(&sizes, &throughput, left, length));
+
+        // sizes are probed in order until one fits
+        let sizes = [Probe::Size(5000), Probe::NotProbed, Probe::Size(500)];
+        assert_eq!(Choice::Probe(1), choose(&sizes, &throughput, left, length));
+        let sizes = [Probe::Size(5000), Probe::Failed, Probe::Size(500)];
+        assert_eq!(Choice::Start(1), choose(&sizes, &throughput, left, length));
+
+        // a video being probed by another slot is skipped, and waited for
+        // when nothing after it fits
+        let sizes = [Probe::Probing, Probe::NotProbed];
+        assert_eq!(Choice::Probe(1), choose(&sizes, &throughput, left, length));
+        let sizes = [Probe::Probing, Probe::Size(500)];
+        assert_eq!(Choice::Start(1), choose(&sizes, &throughput, left, length));
+        let sizes = [Probe::Probing, Probe::Size(20_000)];
+        assert_eq!(Choice::Wait, choose(&sizes, &throughput, left, length));
+
+        // longer than the whole window only when nothing else fits
+        let sizes = [Probe::Size(5000), Probe::Size(20_000)];
+        assert_eq!(Choice::Start(1), choose(&sizes, &throughput, left, length));
+        assert_eq!(
+            Choice::Defer,
+            choose(&[Probe::Size(5000)], &throughput, left, length)
+        );
+    }
+}
@@ -0,0 +1,112 @@
+use futures::stream::{self, StreamExt as _};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+use crate::display;
+use crate::timer::{Schedule, TimedDownload as _};
+use crate::utils::parse_video;
+use crate::video::Video;
+
+mod fit;
+
+pub struct BatchReport {
+    pub invalid: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub cancelled: bool,
+}
+
+impl BatchReport {
+    pub fn is_clean(&self) -> bool {
+        self.invalid == 0 && self.failed == 0
+    }
+}
+
+pub fn parse_batch(file_content: &str) -> (Vec<Video>, usize) {
+    let mut videos = Vec::new();
+    let mut invalid = 0;
+    for line in file_content.lines() {
+        match parse_video(line) {
+            Ok(video) => videos.push(video),
+            Err(err) => {
+                invalid += 1;
+                error!("'{}' is not a valid link: {}", line, err);
+            }
+        }
+    }
+    (videos, invalid)
+}
+
+/// downloads the videos of a batch file, `parallel` of them at a time,
+/// honoring the timer
+pub async fn download_batch(
+    file_content: &str,
+    time_range: &Option<Schedule>,
+    parallel: usize,
+    cancel: &CancellationToken,
+) -> BatchReport {
+    let (videos, invalid) = parse_batch(file_content);
+    let mut report = BatchReport {
+        invalid,
+        succeeded: 0,
+        failed: 0,
+        cancelled: false,
+    };
+
+    info!("Parsed all videos, count: {}", videos.len());
+    info!("Starting download");
+    if parallel > 1 {
+        display::start_batch(videos.len(), time_range.is_some());
+    }
+
+    let results: Vec<Option<bool>> = match time_range {
+        Some(timer) if timer.whole_videos() => {
+            fit::download_fitting(videos, timer, parallel, cancel).await
+        }
+        _ => {
+            stream::iter(videos)
+                .map(|video| run_job(video, time_range, cancel))
+                .buffer_unordered(parallel.max(1))
+                .collect()
+                .await
+        }
+    };
+    display::finish_batch();
+
+    for result in results {
+        match result {
+            Some(true) => report.succeeded += 1,
+            Some(false) => report.failed += 1,
+            None => report.cancelled = true,
+        }
+    }
+
+    report
+}
+
+/// `None` if cancelled before the download started
+async fn run_job(
+    mut video: Video,
+    time_range: &Option<Schedule>,
+    cancel: &CancellationToken,
+) -> Option<bool> {
+    if cancel.is_cancelled() {
+        return None;
+    }
+    if let Some(timer) = time_range {
+        video.set_time_range(timer.clone()).await;
+    }
+    tokio::select! {
+        _ = cancel.cancelled() => return None,
+        _ = time_range.wait_until_in_range() => (),
+    }
+    let result = video.download(true, cancel.child_token()).await;
+    display::job_finished();
+    match result {
+        Ok(_) => Some(true),
+        Err(x) => {
+            error!("Error message: '{}'", x);
+            Some(false)
+        }
+    }
+}
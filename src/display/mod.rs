@@ -0,0 +1,241 @@
+use kdam::term::{self, Writer};
+use kdam::{tqdm, Bar, BarExt};
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+
+use crate::events;
+
+const STATUS_ROW: u16 = 0;
+const BATCH_ROW: u16 = 1;
+const FIRST_VIDEO_ROW: u16 = 2;
+const TITLE_WIDTH: usize = 24;
+
+/// terminal layout while several videos download: a status line, the overall
+/// batch bar and one bar for every active video below them
+struct BatchDisplay {
+    bar: Bar,
+    jobs_total: usize,
+    jobs_done: usize,
+    active: usize,
+    /// `None` when no timer is set
+    timer_paused: Option<bool>,
+    quota_paused: bool,
+    free_rows: BTreeSet<u16>,
+    next_row: u16,
+}
+
+static BATCH: Mutex<Option<BatchDisplay>> = Mutex::new(None);
+
+impl BatchDisplay {
+    fn draw_status(&self) {
+        let timer = match self.timer_paused {
+            Some(true) => "paused, waiting for the window to open",
+            Some(false) => "window open",
+            None => "off",
+        };
+        let quota = if self.quota_paused {
+            " | quota reached, waiting for reset"
+        } else {
+            ""
+        };
+        let status = format!(
+            "{} active, {}/{} jobs done | timer: {}{}\x1b[K",
+            self.active, self.jobs_done, self.jobs_total, timer, quota
+        );
+        let _ = Writer::Stderr.print_at(STATUS_ROW, status.as_bytes());
+    }
+
+    fn draw_batch(&mut self) {
+        self.bar
+            .set_description(format!("batch {}/{}", self.jobs_done, self.jobs_total));
+        let _ = self.bar.refresh();
+    }
+}
+
+/// switches from a single bar to the multi line display, it stays off with json progress
+pub fn start_batch(jobs_total: usize, has_timer: bool) {
+    if events::is_json() {
+        return;
+    }
+    term::init(false);
+    let _ = term::hide_cursor();
+
+    let mut display = BatchDisplay {
+        bar: tqdm!(
+            total = 0,
+            position = BATCH_ROW,
+            unit_scale = true,
+            unit_divisor = 1024,
+            unit = "B",
+            force_refresh = true
+        ),
+        jobs_total,
+        jobs_done: 0,
+        active: 0,
+        timer_paused: has_timer.then_some(false),
+        quota_paused: false,
+        free_rows: BTreeSet::new(),
+        next_row: FIRST_VIDEO_ROW,
+    };
+    display.draw_status();
+    display.draw_batch();
+    *BATCH.lock().unwrap() = Some(display);
+}
+
+/// moves the cursor below every row that was used and restores the cursor
+pub fn finish_batch() {
+    if let Some(display) = BATCH.lock().unwrap().take() {
+        eprint!("{}", "\n".repeat(display.next_row as usize));
+        let _ = term::show_cursor();
+    }
+}
+
+pub fn job_added() {
+    if let Some(display) = BATCH.lock().unwrap().as_mut() {
+        display.jobs_total += 1;
+        display.draw_status();
+        display.draw_batch();
+    }
+}
+
+pub fn job_finished() {
+    if let Some(display) = BATCH.lock().unwrap().as_mut() {
+        display.jobs_done += 1;
+        display.draw_status();
+        display.draw_batch();
+    }
+}
+
+pub fn set_timer_paused(paused: bool) {
+    if let Some(display) = BATCH.lock().unwrap().as_mut() {
+        if display.timer_paused != Some(paused) {
+            display.timer_paused = Some(paused);
+            display.draw_status();
+        }
+    }
+}
+
+pub fn set_quota_paused(paused: bool) {
+    if let Some(display) = BATCH.lock().unwrap().as_mut() {
+        if display.quota_paused != paused {
+            display.quota_paused = paused;
+            display.draw_status();
+        }
+    }
+}
+
+/// progress bar of a single video, it takes a row of the batch display when
+/// one is shown and counts towards the batch bar as well
+pub struct VideoBar {
+    bar: Bar,
+    row: Option<u16>,
+    media_total: f64,
+    media_done: f64,
+    /// shown when it is chosen adaptively
+    concurrency: Option<usize>,
+}
+
+impl VideoBar {
+    pub fn new(title: &str, total: usize, media_total: f64) -> Self {
+        let mut batch = BATCH.lock().unwrap();
+        let Some(display) = batch.as_mut() else {
+            let bar = tqdm!(
+                total = total,
+                unit_scale = true,
+                unit_divisor = 1024,
+                unit = "B",
+                disable = events::is_json()
+            );
+            return Self::with_media(bar, None, media_total);
+        };
+
+        let row = match display.free_rows.pop_first() {
+            Some(row) => row,
+            None => {
+                display.next_row += 1;
+                display.next_row - 1
+            }
+        };
+        display.active += 1;
+        display.bar.total += total;
+        display.draw_status();
+        display.draw_batch();
+
+        let desc: String = title.chars().take(TITLE_WIDTH).collect();
+        let bar = tqdm!(
+            total = total,
+            desc = format!("{:<width$}", desc, width = TITLE_WIDTH),
+            position = row,
+            unit_scale = true,
+            unit_divisor = 1024,
+            unit = "B"
+        );
+        Self::with_media(bar, Some(row), media_total)
+    }
+
+    fn with_media(mut bar: Bar, row: Option<u16>, media_total: f64) -> Self {
+        bar.set_postfix(format!("media {}", format_media(0.0, media_total)));
+        Self {
+            bar,
+            row,
+            media_total,
+            media_done: 0.0,
+            concurrency: None,
+        }
+    }
+
+    pub fn set_concurrency(&mut self, level: usize) {
+        self.concurrency = Some(level);
+        self.draw_postfix();
+        let _ = self.bar.refresh();
+    }
+
+    fn draw_postfix(&mut self) {
+        let media = format_media(self.media_done, self.media_total);
+        match self.concurrency {
+            Some(level) => self
+                .bar
+                .set_postfix(format!("media {}, {} parallel", media, level)),
+            None => self.bar.set_postfix(format!("media {}", media)),
+        }
+    }
+
+    /// `media` is the duration in seconds the downloaded bytes play for
+    pub fn update(&mut self, bytes: usize, media: f64) {
+        self.media_done += media;
+        self.draw_postfix();
+        let _ = self.bar.update(bytes);
+        if self.row.is_some() {
+            if let Some(display) = BATCH.lock().unwrap().as_mut() {
+                let _ = display.bar.update(bytes);
+            }
+        }
+    }
+}
+
+impl Drop for VideoBar {
+    fn drop(&mut self) {
+        let Some(row) = self.row else {
+            return;
+        };
+        let _ = self.bar.clear();
+        if let Some(display) = BATCH.lock().unwrap().as_mut() {
+            display.free_rows.insert(row);
+            display.active -= 1;
+            display.draw_status();
+        }
+    }
+}
+
+fn format_media(done: f64, total: f64) -> String {
+    let clock = |seconds: f64| {
+        let seconds = seconds as u64;
+        format!(
+            "{:02}:{:02}:{:02}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    };
+    format!("{}/{}", clock(done), clock(total))
+}
@@ -0,0 +1,75 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/// prints a human readable line, it goes to stderr when stdout carries json events
+macro_rules! report {
+    ($($arg:tt)*) => {
+        if $crate::events::is_json() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// machine readable progress, printed as one json object per line with `--progress json`
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    JobStarted {
+        video_id: String,
+        host: String,
+    },
+    QualitySelected {
+        video_id: String,
+        title: String,
+        quality: String,
+        size: usize,
+    },
+    SegmentDone {
+        video_id: String,
+        index: usize,
+        bytes: usize,
+        /// seconds of media in the segment
+        duration: f64,
+        /// finished by an earlier run and only verified now
+        resumed: bool,
+    },
+    Retry {
+        video_id: String,
+        index: usize,
+        attempt: usize,
+        reason: String,
+    },
+    /// parts downloaded at the same time, only sent with `--concurrency auto`
+    ConcurrencyChanged {
+        video_id: String,
+        level: usize,
+    },
+    JobFinished {
+        video_id: String,
+        output: String,
+    },
+    JobFailed {
+        video_id: String,
+        error: String,
+    },
+}
+
+pub fn enable_json() {
+    JSON_OUTPUT.store(true, Ordering::Relaxed);
+}
+
+pub fn is_json() -> bool {
+    JSON_OUTPUT.load(Ordering::Relaxed)
+}
+
+pub fn emit(event: Event) {
+    if is_json() {
+        if let Ok(line) = serde_json::to_string(&event) {
+            println!("{}", line);
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use hyper::client::connect::dns::Name;
+use reqwest::{
+    dns::{Addrs, Resolve, Resolving},
+    header, Client,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+const RECORD_A: u16 = 1;
+const RECORD_AAAA: u16 = 28;
+
+#[derive(Deserialize)]
+struct DnsResponse {
+    #[serde(rename = "Status")]
+    status: u32,
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DnsAnswer>,
+}
+
+#[derive(Deserialize)]
+struct DnsAnswer {
+    #[serde(rename = "type")]
+    record_type: u16,
+    #[serde(rename = "TTL")]
+    ttl: u64,
+    data: String,
+}
+
+/// addresses of a host and when they expire
+type Cache = HashMap<String, (Instant, Vec<IpAddr>)>;
+
+/// resolves hosts through a DNS-over-HTTPS server speaking the json api of
+/// cloudflare and google (`application/dns-json`), answers are cached for their ttl
+pub struct DohResolver {
+    url: String,
+    client: Client,
+    cache: Arc<Mutex<Cache>>,
+}
+
+impl DohResolver {
+    /// the server itself is resolved by the system, or given as an ip
+    pub fn new(url: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self {
+            url: url::Url::parse(url)?.to_string(),
+            client: Client::builder().no_proxy().build()?,
+            cache: Default::default(),
+        })
+    }
+}
+
+impl Resolve for DohResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let url = self.url.clone();
+        let client = self.client.clone();
+        let cache = self.cache.clone();
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let cached = cache
+                .lock()
+                .unwrap()
+                .get(&host)
+                .filter(|(expires, _)| *expires > Instant::now())
+                .map(|(_, addresses)| addresses.clone());
+            let addresses = match cached {
+                Some(addresses) => addresses,
+                None => {
+                    let (addresses, ttl) = lookup(&client, &url, &host).await?;
+                    debug!("Resolved {} to {:?} over https", &host, &addresses);
+                    let expires = Instant::now() + Duration::from_secs(ttl);
+                    cache
+                        .lock()
+                        .unwrap()
+                        .insert(host, (expires, addresses.clone()));
+                    addresses
+                }
+            };
+            // the port is filled in by the connector
+            let addrs: Addrs = Box::new(
+                addresses
+                    .into_iter()
+                    .map(|address| SocketAddr::new(address, 0)),
+            );
+            Ok(addrs)
+        })
+    }
+}
+
+/// ipv4 addresses of `host`, ipv6 ones if it has none, and the lowest ttl
+async fn lookup(
+    client: &Client,
+    url: &str,
+    host: &str,
+) -> Result<(Vec<IpAddr>, u64), Box<dyn std::error::Error + Send + Sync>> {
+    for record_type in ["A", "AAAA"] {
+        let body = client
+            .get(url)
+            .query(&[("name", host), ("type", record_type)])
+            .header(header::ACCEPT, "application/dns-json")
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        let response: DnsResponse = serde_json::from_slice(&body)?;
+        let (addresses, ttl) = addresses(response)?;
+        if !addresses.is_empty() {
+            return Ok((addresses, ttl));
+        }
+    }
+    Err(format!("{} has no address", host).into())
+}
+
+/// cname records of the answer are skipped, their targets follow them
+fn addresses(response: DnsResponse) -> Result<(Vec<IpAddr>, u64), String> {
+    // 3 is NXDOMAIN
+    if response.status != 0 {
+        return Err(format!(
+            "dns server answered with status {}",
+            response.status
+        ));
+    }
+    let mut addresses = Vec::new();
+    let mut ttl = u64::MAX;
+    for answer in response.answer {
+        if answer.record_type != RECORD_A && answer.record_type != RECORD_AAAA {
+            continue;
+        }
+        let address = answer
+            .data
+            .parse()
+            .map_err(|_| format!("'{}' is not an ip address", answer.data))?;
+        addresses.push(address);
+        ttl = ttl.min(answer.ttl);
+    }
+    Ok((addresses, ttl))
+}
+
+#[cfg(test)]
+mod dns_tests {
+    use super::*;
+
+    #[test]
+    fn json_answers() -> Result<(), Box<dyn std::error::Error>> {
+        let response = r#"{"Status":0,"Answer":[
+            {"name":"stream.example.com","type":5,"TTL":300,"data":"cdn.example.net."},
+            {"name":"cdn.example.net","type":1,"TTL":60,"data":"203.0.113.7"},
+            {"name":"cdn.example.net","type":1,"TTL":120,"data":"203.0.113.8"}]}"#;
+        let (found, ttl) = addresses(serde_json::from_str(response)?)?;
+        assert_eq!(
+            vec!["203.0.113.7".parse::<IpAddr>()?, "203.0.113.8".parse()?],
+            found
+        );
+        assert_eq!(60, ttl);
+
+        let empty = r#"{"Status":0}"#;
+        assert!(addresses(serde_json::from_str(empty)?)?.0.is_empty());
+        let missing = r#"{"Status":3}"#;
+        assert!(addresses(serde_json::from_str(missing)?).is_err());
+
+        Ok(())
+    }
+}
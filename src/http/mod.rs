@@ -0,0 +1,362 @@
+use reqwest::{
+    cookie::Jar,
+    header::{self, HeaderMap, HeaderName, HeaderValue},
+    Client, ClientBuilder, NoProxy, Proxy, RequestBuilder,
+};
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::Url;
+
+use crate::arguments::{Http2Mode, KavimoArgs, ProxyScope};
+
+mod dns;
+use dns::DohResolver;
+
+const USER_AGENT: &str =
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:122.0) Gecko/20100101 Firefox/122.0";
+
+/// settings every client is built with, taken from the command line once
+pub struct ClientOptions {
+    user_agent: Option<String>,
+    referer: Option<String>,
+    headers: HeaderMap,
+    proxy: Option<String>,
+    proxy_scope: ProxyScope,
+    pool_size: Option<usize>,
+    pool_idle_timeout: u64,
+    tcp_keepalive: Option<u64>,
+    http2: Http2Mode,
+    resolve: Vec<(String, SocketAddr)>,
+    doh: Option<String>,
+}
+
+/// `ClientOptions` with the proxy and resolver set up, without `--proxy` the
+/// `HTTP_PROXY`, `HTTPS_PROXY`, `ALL_PROXY` and `NO_PROXY` variables are used
+struct HttpConfig {
+    options: ClientOptions,
+    proxy: Option<Proxy>,
+    resolver: Option<Arc<DohResolver>>,
+}
+
+static CONFIG: OnceLock<HttpConfig> = OnceLock::new();
+/// cookies of every client, filled from `cookies.txt` files and by the servers
+static COOKIES: OnceLock<Arc<Jar>> = OnceLock::new();
+/// api and segment clients of every video host, videos of the same host
+/// share their connection pools
+static CLIENTS: Mutex<Option<HashMap<String, (Client, Client)>>> = Mutex::new(None);
+
+impl From<&KavimoArgs> for ClientOptions {
+    fn from(args: &KavimoArgs) -> Self {
+        Self {
+            user_agent: args.user_agent.clone(),
+            referer: args.referer.clone(),
+            headers: args.header.iter().cloned().collect(),
+            proxy: args.proxy.clone(),
+            proxy_scope: args.proxy_scope,
+            pool_size: args.pool_size,
+            pool_idle_timeout: args.pool_idle_timeout,
+            tcp_keepalive: args.tcp_keepalive,
+            http2: args.http2,
+            resolve: args.resolve.clone(),
+            doh: args.doh.clone(),
+        }
+    }
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            user_agent: None,
+            referer: None,
+            headers: HeaderMap::new(),
+            proxy: None,
+            proxy_scope: ProxyScope::All,
+            pool_size: None,
+            pool_idle_timeout: 90,
+            tcp_keepalive: None,
+            http2: Http2Mode::Auto,
+            resolve: Vec::new(),
+            doh: None,
+        }
+    }
+}
+
+/// headers of a single video on top of the command line ones, set from its batch line
+#[derive(Clone, Default)]
+pub struct Identity {
+    pub user_agent: Option<String>,
+    pub referer: Option<String>,
+    pub headers: HeaderMap,
+    /// cookies of this video only, they replace the `--cookies` jar
+    pub cookies: Option<Arc<Jar>>,
+}
+
+impl Identity {
+    /// `default_referer` is used when neither the video nor `--referer` sets one
+    pub fn apply(&self, request: RequestBuilder, default_referer: String) -> RequestBuilder {
+        let referer = self
+            .referer
+            .clone()
+            .or_else(|| config().options.referer.clone())
+            .unwrap_or(default_referer);
+        let mut request = request
+            .header(header::REFERER, referer)
+            .headers(self.headers.clone());
+        if let Some(user_agent) = &self.user_agent {
+            request = request.header(header::USER_AGENT, user_agent);
+        }
+        request
+    }
+}
+
+/// `Name: value` as given to `--header`
+pub fn parse_header(input: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = input
+        .split_once(':')
+        .ok_or(format!("'{}' is not a 'Name: value' header", input))?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes()).map_err(|err| err.to_string())?;
+    let value = HeaderValue::from_str(value.trim()).map_err(|err| err.to_string())?;
+    Ok((name, value))
+}
+
+/// adds the cookies of a netscape `cookies.txt`, as exported by browser
+/// extensions or curl, to the jar shared by every client
+pub fn load_cookies(path: &str) -> Result<usize, Box<dyn std::error::Error>> {
+    add_cookies(&cookie_jar(), path)
+}
+
+/// a jar of its own for the `cookies=` option of a batch line, so the
+/// cookies are not sent with the requests of other videos
+pub fn load_video_cookies(path: &str) -> Result<Arc<Jar>, Box<dyn std::error::Error>> {
+    let jar = Arc::new(Jar::default());
+    add_cookies(&jar, path)?;
+    Ok(jar)
+}
+
+fn add_cookies(jar: &Jar, path: &str) -> Result<usize, Box<dyn std::error::Error>> {
+    let content = fs::read_to_string(path)?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut count = 0;
+    for line in content.lines() {
+        if let Some((cookie, url)) = parse_cookie_line(line, now)? {
+            jar.add_cookie_str(&cookie, &url);
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// `Set-Cookie` value and url of a line, `None` for comments and expired cookies
+fn parse_cookie_line(
+    line: &str,
+    now: u64,
+) -> Result<Option<(String, Url)>, Box<dyn std::error::Error>> {
+    let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+    if line.starts_with('#') || line.trim().is_empty() {
+        return Ok(None);
+    }
+    let fields: Vec<&str> = line.trim_end_matches('\r').split('\t').collect();
+    let [domain, subdomains, path, secure, expires, name, value] = fields[..] else {
+        return Err(format!("'{}' is not a cookies.txt line", line).into());
+    };
+    // zero means a session cookie
+    let expires: u64 = expires.parse()?;
+    if expires != 0 && expires < now {
+        return Ok(None);
+    }
+    let host = domain.trim_start_matches('.');
+    let url = Url::parse(&format!("https://{}{}", host, path))?;
+    let mut cookie = format!("{}={}; Path={}", name, value, path);
+    if subdomains == "TRUE" {
+        cookie.push_str(&format!("; Domain={}", host));
+    }
+    if secure == "TRUE" {
+        cookie.push_str("; Secure");
+    }
+    Ok(Some((cookie, url)))
+}
+
+fn cookie_jar() -> Arc<Jar> {
+    COOKIES.get_or_init(Default::default).clone()
+}
+
+fn config() -> &'static HttpConfig {
+    CONFIG.get_or_init(|| HttpConfig {
+        options: ClientOptions::default(),
+        proxy: None,
+        resolver: None,
+    })
+}
+
+/// the proxy accepts http, https and socks5 urls, hosts in `NO_PROXY` still go direct
+pub fn init(options: ClientOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let proxy = match &options.proxy {
+        Some(url) => {
+            let proxy = Proxy::all(url)
+                .map_err(|err| format!("'{}' is not a valid proxy: {}", url, err))?;
+            Some(proxy.no_proxy(NoProxy::from_env()))
+        }
+        None => None,
+    };
+    let resolver = match &options.doh {
+        Some(url) => {
+            let resolver = DohResolver::new(url)
+                .map_err(|err| format!("'{}' is not a valid DoH server: {}", url, err))?;
+            Some(Arc::new(resolver))
+        }
+        None => None,
+    };
+    let _ = CONFIG.set(HttpConfig {
+        options,
+        proxy,
+        resolver,
+    });
+    // an invalid `--user-agent` or a TLS backend that cannot start fails here
+    // instead of with the first video
+    build_clients(cookie_jar())?;
+    Ok(())
+}
+
+/// ports of http and https urls, the only ones `--resolve` accepts
+const URL_PORTS: [u16; 2] = [80, 443];
+
+/// `host:port:addr` as curl takes it, `addr` may be a bracketed ipv6 address.
+/// reqwest overrides a host for all of its ports, so only the ports of plain
+/// http and https urls are accepted
+pub fn parse_resolve(input: &str) -> Result<(String, SocketAddr), String> {
+    let mut parts = input.splitn(3, ':');
+    let (Some(host), Some(port), Some(address)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(format!("'{}' is not in host:port:addr form", input));
+    };
+    let port: u16 = port
+        .parse()
+        .map_err(|_| format!("'{}' is not a port", port))?;
+    if !URL_PORTS.contains(&port) {
+        return Err(format!(
+            "port {} is not supported, the address is used for every port of the host, give 80 or 443",
+            port
+        ));
+    }
+    // only literal addresses, looking a name up would use the resolver this bypasses
+    let address = address.trim_start_matches('[').trim_end_matches(']');
+    let address: IpAddr = address
+        .parse()
+        .map_err(|_| format!("'{}' is not an ip address", address))?;
+    Ok((host.to_lowercase(), SocketAddr::new(address, port)))
+}
+
+/// clients for the embed, playlist and key requests and for the segments of
+/// a host, built on first use; a video with cookies of its own gets clients
+/// that are not shared
+pub fn clients(host: &str, identity: &Identity) -> Result<(Client, Client), reqwest::Error> {
+    if let Some(jar) = &identity.cookies {
+        return build_clients(jar.clone());
+    }
+    let mut clients = CLIENTS.lock().unwrap();
+    let clients = clients.get_or_insert_with(HashMap::new);
+    if let Some(pair) = clients.get(host) {
+        return Ok(pair.clone());
+    }
+    let pair = build_clients(cookie_jar())?;
+    clients.insert(host.to_string(), pair.clone());
+    Ok(pair)
+}
+
+fn build_clients(jar: Arc<Jar>) -> Result<(Client, Client), reqwest::Error> {
+    let config = config();
+    let build = |proxied: bool| config.builder(proxied, jar.clone()).build();
+    Ok(match config.options.proxy_scope {
+        ProxyScope::All => {
+            let client = build(true)?;
+            (client.clone(), client)
+        }
+        ProxyScope::Segments => (build(false)?, build(true)?),
+        ProxyScope::Api => (build(true)?, build(false)?),
+    })
+}
+
+impl HttpConfig {
+    fn builder(&self, proxied: bool, jar: Arc<Jar>) -> ClientBuilder {
+        let options = &self.options;
+        let user_agent = options.user_agent.as_deref().unwrap_or(USER_AGENT);
+        let mut builder = ClientBuilder::new()
+            .user_agent(user_agent)
+            .default_headers(options.headers.clone())
+            .cookie_provider(jar)
+            .pool_idle_timeout(Duration::from_secs(options.pool_idle_timeout))
+            .tcp_keepalive(options.tcp_keepalive.map(Duration::from_secs));
+        if let Some(pool_size) = options.pool_size {
+            builder = builder.pool_max_idle_per_host(pool_size);
+        }
+        builder = match options.http2 {
+            Http2Mode::Auto => builder,
+            Http2Mode::Off => builder.http1_only(),
+            Http2Mode::Always => builder.http2_prior_knowledge(),
+        };
+        if let Some(resolver) = &self.resolver {
+            builder = builder.dns_resolver(resolver.clone());
+        }
+        // reqwest connects to the port of the url, which is 80 or 443 here
+        for (host, address) in &options.resolve {
+            builder = builder.resolve(host, *address);
+        }
+        if !proxied {
+            return builder.no_proxy();
+        }
+        match &self.proxy {
+            Some(proxy) => builder.proxy(proxy.clone()),
+            None => builder,
+        }
+    }
+}
+
+#[cfg(test)]
+mod http_tests {
+    use super::*;
+
+    #[test]
+    fn cookies_txt_lines() -> Result<(), Box<dyn std::error::Error>> {
+        let now = 1_700_000_000;
+        let (cookie, url) = parse_cookie_line(
+            ".example.com\tTRUE\t/\tTRUE\t1800000000\tsession\tabc123",
+            now,
+        )?
+        .unwrap();
+        assert_eq!("session=abc123; Path=/; Domain=example.com; Secure", cookie);
+        assert_eq!("https://example.com/", url.as_str());
+
+        let (cookie, _) = parse_cookie_line(
+            "#HttpOnly_lms.example.com\tFALSE\t/courses\tFALSE\t0\tid\t42",
+            now,
+        )?
+        .unwrap();
+        assert_eq!("id=42; Path=/courses", cookie);
+
+        assert!(parse_cookie_line("# Netscape HTTP Cookie File", now)?.is_none());
+        assert!(parse_cookie_line("a.com\tTRUE\t/\tFALSE\t1600000000\told\tx", now)?.is_none());
+        assert!(parse_cookie_line("a.com session=abc", now).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_overrides() -> Result<(), String> {
+        let (host, address) = parse_resolve("Stream.Biomaze.ir:443:10.0.0.5")?;
+        assert_eq!("stream.biomaze.ir", host);
+        assert_eq!("10.0.0.5:443", address.to_string());
+
+        let (_, address) = parse_resolve("example.com:80:[2001:db8::1]")?;
+        assert_eq!("[2001:db8::1]:80", address.to_string());
+
+        assert!(parse_resolve("example.com:10.0.0.5").is_err());
+        assert!(parse_resolve("example.com:443:not-an-ip").is_err());
+        assert!(parse_resolve("example.com:443:localhost").is_err());
+        // the override would apply to 443 as well
+        assert!(parse_resolve("example.com:8443:10.0.0.5").is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+use std::path::Path;
+
+use tracing::level_filters::LevelFilter;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::prelude::*;
+
+use crate::events;
+
+/// sets up console logging and, if `log_file` is given, a json log file
+/// rotated daily that always records at least debug messages
+///
+/// the returned guard flushes the log file when dropped, keep it alive in main
+pub fn init(
+    verbose: u8,
+    quiet: u8,
+    log_file: Option<&str>,
+) -> Result<Option<WorkerGuard>, Box<dyn std::error::Error>> {
+    let level = match verbose as i16 - quiet as i16 {
+        ..=-2 => LevelFilter::ERROR,
+        -1 => LevelFilter::WARN,
+        0 => LevelFilter::INFO,
+        1 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    };
+
+    // stdout belongs to json events when they are enabled
+    let console_writer = if events::is_json() {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
+    let console = tracing_subscriber::fmt::layer()
+        .without_time()
+        .with_target(false)
+        .with_writer(console_writer)
+        .with_filter(filter(level));
+
+    let (file, guard) = match log_file {
+        Some(log_file) => {
+            let path = Path::new(log_file);
+            let directory = match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            let file_name = path.file_name().ok_or("log file path has no file name")?;
+            let appender = tracing_appender::rolling::daily(directory, file_name);
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            let layer = tracing_subscriber::fmt::layer()
+                .json()
+                .with_writer(writer)
+                .with_filter(filter(level.max(LevelFilter::DEBUG)));
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    tracing_subscriber::registry()
+        .with(console)
+        .with(file)
+        .try_init()?;
+
+    Ok(guard)
+}
+
+/// our own messages at `level`, dependencies like hyper only from warnings up
+fn filter(level: LevelFilter) -> Targets {
+    Targets::new()
+        .with_target(env!("CARGO_CRATE_NAME"), level)
+        .with_default(level.min(LevelFilter::WARN))
+}
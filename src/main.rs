@@ -1,100 +1,200 @@
 use std::fs::read_to_string;
 use std::io::stdin;
+use std::process::ExitCode;
 use clap::Parser as _;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
 
+#[macro_use]
+mod events;
+mod logging;
 mod video;
 mod arguments;
+mod batch;
+mod display;
+mod http;
+mod quota;
+mod serve;
 mod timer;
 mod utils;
+mod watch;
 use utils::*;
 
-use crate::timer::TimedDownload as _;
+use crate::arguments::{KavimoCommand, ProgressFormat};
 
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     let args = arguments::KavimoArgs::parse();
+    if args.progress == ProgressFormat::Json {
+        events::enable_json();
+    }
+
+    let _log_guard = match logging::init(args.verbose, args.quiet, args.log_file.as_deref()) {
+        Ok(guard) => guard,
+        Err(err) => {
+            eprintln!("Cannot set up logging due {}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+
     if !args.validate() {
-        return ;
+        return ExitCode::FAILURE;
+    }
+
+    if let Err(err) = http::init(http::ClientOptions::from(&args)) {
+        error!("Cannot set up http clients due {}", err);
+        return ExitCode::FAILURE;
+    }
+
+    video::set_mirrors(args.mirror.clone());
+    video::set_concurrency(args.concurrency);
+    video::set_audio_only(args.audio_only);
+    video::set_section(args.section);
+    video::set_renditions(video::RenditionChoice {
+        audio: args.audio_lang.clone(),
+        subtitles: args.subtitle_lang.clone(),
+        is_sidecar: args.subtitle_sidecar,
+    });
+
+    if let Some(cookies) = &args.cookies {
+        match http::load_cookies(cookies) {
+            Ok(count) => info!("Loaded {} cookies from '{}'", count, cookies),
+            Err(err) => {
+                error!("Cannot load cookies from '{}' due {}", cookies, err);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let timer_text = match (&args.timer, &args.timer_file) {
+        (Some(timer), _) => Some(timer.clone()),
+        (None, Some(timer_file)) => match read_to_string(timer_file) {
+            Ok(file_content) => Some(file_content),
+            Err(err) => {
+                error!("Cannot open timer file: '{}' due {}", timer_file, err);
+                return ExitCode::FAILURE;
+            }
+        },
+        (None, None) => None
+    };
+
+    let timezone = match &args.timezone {
+        Some(x) => match timer::parse_timezone(x) {
+            Ok(timezone) => Some(timezone),
+            Err(err) => {
+                error!("'{}' is not a valid timezone: {}", x, err);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None
+    };
+    let clock = timer::Clock::new(timezone);
+
+    let time_range = match timer_text {
+        Some(x) => {
+            match timer::parse_schedule(&x) {
+                Ok(mut schedule) => {
+                    schedule.set_whole_videos(args.whole_videos);
+                    schedule.set_clock(clock);
+                    Some(schedule)
+                },
+                Err(err) => {
+                    error!("'{}' is not a valid timer: {}", x.trim(), err);
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        None => None
+    };
+
+    if args.daily_quota.is_some() || args.monthly_quota.is_some() {
+        quota::init(quota::Quota::new(args.daily_quota, args.monthly_quota, clock, time_range.clone()));
+    }
+
+    let shutdown = CancellationToken::new();
+    tokio::spawn(cancel_on_ctrl_c(shutdown.clone()));
+
+    // returning instead of exiting lets the log guard flush the last lines of `--log-file`
+    match args.command {
+        Some(KavimoCommand::Watch { dir, interval }) => {
+            let watch = watch::watch_directory(&dir, interval, time_range, args.parallel, shutdown);
+            let result = watch.await;
+            quota::flush();
+            if let Err(err) = result {
+                error!("Cannot watch directory '{}' due {}", &dir, err);
+                return ExitCode::FAILURE;
+            }
+            return ExitCode::SUCCESS;
+        }
+        Some(KavimoCommand::Serve { port }) => {
+            let result = serve::serve(port, time_range, args.parallel, shutdown).await;
+            quota::flush();
+            if let Err(err) = result {
+                error!("Cannot serve on port {} due {}", port, err);
+                return ExitCode::FAILURE;
+            }
+            return ExitCode::SUCCESS;
+        }
+        None => ()
     }
 
     if let Some(batch_file) = args.file {
         match read_to_string(&batch_file) {
             Ok(file_content) => {
-                let mut videos = Vec::new();
-                let time_range = match args.timer {
-                    Some(x) => {
-                        match timer::parse_time(&x) {
-                            Ok(time_range) => Some(time_range),
-                            Err(_) => {
-                                println!("[ERROR] '{}' is not a valid timer", &x);
-                                return ;
-                            }
-                        }
-                    }
-                    None => None
-                };
-                for line in file_content.lines() {
-                    if let Ok(video) = parse_video(line) {
-                        videos.push(video);
-                    } else {
-                        println!("[ERROR] '{}' is not a valid link", line);
-                    }
-                }
-                println!("[Progress] Parsed all videos, count: {}", videos.len());
-                println!("[Progress] Starting download");
-                for mut video in videos {
-                    if let Some(ref timer) = time_range {
-                        video.set_time_range(timer.clone()).await;
-                    }
-                    time_range.should_coutinue();
-                    match video.download(true).await {
-                        Ok(_) => (),
-                        Err(x) => {
-                            println!("[ERROR] Error message: '{}'", x.to_string());
-                        }
-                    }
+                let report = batch::download_batch(&file_content, &time_range, args.parallel, &shutdown).await;
+                quota::flush();
+                if report.cancelled || shutdown.is_cancelled() {
+                    info!("Batch stopped, run the same command again to resume");
+                    return ExitCode::from(130);
                 }
+                return ExitCode::SUCCESS;
             }
             Err(err) => {
-                println!(
+                error!(
                     "Cannot open input file: '{}' due {}",
                     &batch_file,
-                    err.to_string()
+                    err
                 );
+                return ExitCode::FAILURE;
             }
         }
-
-        std::process::exit(0);
     }
 
-    println!("Enter video iframe url: (e.g. https://stream.kavimo.com/chn2rbqavgjt/embed)");
+    report!("Enter video iframe url: (e.g. https://stream.kavimo.com/chn2rbqavgjt/embed)");
     let mut user_input = String::new();
-    let mut input_valid = false;
 
-    while !input_valid {
+    loop {
         user_input.clear();
         stdin().read_line(&mut user_input).unwrap();
         // user_input = "https://stream.biomaze.ir/b6tnnbbopku1/iframe".to_string();
         user_input = user_input.trim().to_owned();
 
         match parse_video(&user_input) {
-            Ok(video) => {
-                input_valid = true;
-
+            Ok(mut video) => {
                 video.print_extracted().await;
+                if let Some(timer) = &time_range {
+                    video.set_time_range(timer.clone()).await;
+                }
 
-                match video.download(false).await {
+                let result = video.download(false, shutdown.clone()).await;
+                quota::flush();
+                match result {
                     Ok(_) => {
-                        std::process::exit(0);
+                        return ExitCode::SUCCESS;
+                    }
+                    Err(_) if shutdown.is_cancelled() => {
+                        info!("Download stopped, run the same command again to resume");
+                        return ExitCode::from(130);
                     }
                     Err(x) => {
-                        println!("[ERROR] Error message: '{}'", x.to_string());
+                        error!("Error message: '{}'", x);
+                        return ExitCode::FAILURE;
                     }
                 };
             }
             Err(_) => {
-                println!("Cannot parse data from url provided try again: ");
+                report!("Cannot parse data from url provided try again: ");
             }
         }
     }
@@ -0,0 +1,352 @@
+use chrono::{Datelike, NaiveDate, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::display;
+use crate::timer::{Clock, Schedule};
+use crate::utils::write_atomically;
+
+const LEDGER_NAME: &str = "kavimo-quota.json";
+/// older days and windows are dropped, two months are enough for the monthly quota
+const KEEP_ENTRIES: usize = 62;
+/// the ledger is saved after this many bytes or seconds, whichever comes first
+const FLUSH_EVERY: u64 = 8 << 20;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+static QUOTA: OnceLock<Quota> = OnceLock::new();
+
+/// generation and serialized content of the ledger at some point
+type Snapshot = (u64, serde_json::Result<Vec<u8>>);
+
+/// bytes transferred per day and per timer window, a month is the sum of its days
+#[derive(Serialize, Deserialize, Default)]
+struct Ledger {
+    days: BTreeMap<String, u64>,
+    windows: BTreeMap<String, u64>,
+    #[serde(skip)]
+    unsaved: u64,
+    #[serde(skip)]
+    flushed_at: Option<Instant>,
+    /// number of the last snapshot taken for saving
+    #[serde(skip)]
+    generation: u64,
+}
+
+pub struct Quota {
+    daily: Option<u64>,
+    monthly: Option<u64>,
+    clock: Clock,
+    time_range: Option<Schedule>,
+    ledger: Mutex<Ledger>,
+    path: PathBuf,
+    /// generation of the snapshot on disk, snapshots are saved off the
+    /// runtime and an older one must not replace a newer one
+    saved: Arc<Mutex<u64>>,
+}
+
+impl Ledger {
+    fn day(&self, date: NaiveDate) -> u64 {
+        self.days.get(&day_key(date)).copied().unwrap_or(0)
+    }
+
+    fn month(&self, date: NaiveDate) -> u64 {
+        let prefix = date.format("%Y-%m-").to_string();
+        self.days
+            .range(prefix.clone()..)
+            .take_while(|(day, _)| day.starts_with(&prefix))
+            .map(|(_, bytes)| bytes)
+            .sum()
+    }
+
+    fn add(&mut self, date: NaiveDate, window: Option<NaiveDateTime>, bytes: u64) {
+        *self.days.entry(day_key(date)).or_default() += bytes;
+        if let Some(start) = window {
+            let key = start.format("%Y-%m-%d %H:%M").to_string();
+            *self.windows.entry(key).or_default() += bytes;
+        }
+        while self.days.len() > KEEP_ENTRIES {
+            self.days.pop_first();
+        }
+        while self.windows.len() > KEEP_ENTRIES {
+            self.windows.pop_first();
+        }
+    }
+}
+
+impl Quota {
+    /// the ledger is kept in the working directory so every run shares it
+    pub fn new(
+        daily: Option<u64>,
+        monthly: Option<u64>,
+        clock: Clock,
+        time_range: Option<Schedule>,
+    ) -> Self {
+        let path = PathBuf::from(LEDGER_NAME);
+        let ledger = fs::read(&path)
+            .ok()
+            .and_then(|content| serde_json::from_slice(&content).ok())
+            .unwrap_or_default();
+        Self {
+            daily,
+            monthly,
+            clock,
+            time_range,
+            ledger: Mutex::new(ledger),
+            path,
+            saved: Arc::default(),
+        }
+    }
+
+    /// name of the exhausted quota and seconds until it resets
+    fn exceeded_at(&self, now: NaiveDateTime) -> Option<(&'static str, u64)> {
+        let ledger = self.ledger.lock().unwrap();
+        let today = now.date();
+        if self
+            .monthly
+            .is_some_and(|quota| ledger.month(today) >= quota)
+        {
+            return Some(("Monthly", seconds_until(now, next_month(today))));
+        }
+        if self.daily.is_some_and(|quota| ledger.day(today) >= quota) {
+            let tomorrow = today.succ_opt().unwrap_or(today);
+            return Some(("Daily", seconds_until(now, tomorrow)));
+        }
+        None
+    }
+
+    /// called for every chunk received, so the ledger is only counted in
+    /// memory and saved once enough has accumulated
+    fn record(&self, bytes: u64) {
+        let window = self
+            .time_range
+            .as_ref()
+            .and_then(|timer| timer.window_start());
+        let mut ledger = self.ledger.lock().unwrap();
+        ledger.add(self.clock.now().date(), window, bytes);
+        ledger.unsaved += bytes;
+        let is_due = ledger.unsaved >= FLUSH_EVERY
+            || ledger
+                .flushed_at
+                .is_none_or(|flushed_at| flushed_at.elapsed() >= FLUSH_INTERVAL);
+        if !is_due {
+            return;
+        }
+        let snapshot = Self::snapshot(&mut ledger);
+        drop(ledger);
+
+        let path = self.path.clone();
+        let saved = self.saved.clone();
+        let save = move || save(&path, &saved, snapshot);
+        // the file is written off the runtime workers
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(save)),
+            Err(_) => save(),
+        }
+    }
+
+    fn snapshot(ledger: &mut Ledger) -> Snapshot {
+        ledger.unsaved = 0;
+        ledger.flushed_at = Some(Instant::now());
+        ledger.generation += 1;
+        (ledger.generation, serde_json::to_vec(&*ledger))
+    }
+
+    /// saves what is still only counted in memory
+    fn flush(&self) {
+        let mut ledger = self.ledger.lock().unwrap();
+        if ledger.unsaved == 0 && ledger.generation == 0 {
+            return;
+        }
+        let snapshot = Self::snapshot(&mut ledger);
+        drop(ledger);
+        save(&self.path, &self.saved, snapshot);
+    }
+}
+
+fn save(path: &Path, saved: &Mutex<u64>, (generation, content): Snapshot) {
+    let mut saved = saved.lock().unwrap();
+    if *saved >= generation {
+        return;
+    }
+    let result = content
+        .map_err(std::io::Error::from)
+        .and_then(|content| write_atomically(path, &content));
+    match result {
+        Ok(()) => *saved = generation,
+        Err(err) => warn!("Cannot save quota ledger due {}", err),
+    }
+}
+
+fn day_key(date: NaiveDate) -> String {
+    date.format("%Y-%m-%d").to_string()
+}
+
+fn next_month(date: NaiveDate) -> NaiveDate {
+    let (year, month) = match date.month() {
+        12 => (date.year() + 1, 1),
+        month => (date.year(), month + 1),
+    };
+    NaiveDate::from_ymd_opt(year, month, 1).unwrap()
+}
+
+fn seconds_until(now: NaiveDateTime, date: NaiveDate) -> u64 {
+    let midnight = date.and_hms_opt(0, 0, 0).unwrap();
+    (midnight - now).num_seconds().max(1) as u64
+}
+
+/// sizes like `500MB`, `2G` or `1.5TiB` in binary units, a plain number is bytes
+pub fn parse_size(input: &str) -> Result<u64, String> {
+    let input = input.trim();
+    let unit_start = input
+        .find(|c: char| c.is_ascii_alphabetic())
+        .unwrap_or(input.len());
+    let (number, unit) = input.split_at(unit_start);
+    let number = number
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|x| *x >= 0.0)
+        .ok_or(format!("'{}' is not a size", input))?;
+    let unit = unit.to_uppercase();
+    let multiplier: u64 = match unit.trim_end_matches("IB").trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(format!("'{}' is not a size unit", unit)),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+pub fn init(quota: Quota) {
+    let _ = QUOTA.set(quota);
+}
+
+/// counts bytes received from the network, verified parts of earlier runs are not
+pub fn record(bytes: usize) {
+    if let Some(quota) = QUOTA.get() {
+        quota.record(bytes as u64);
+    }
+}
+
+/// saves the ledger before the process exits
+pub fn flush() {
+    if let Some(quota) = QUOTA.get() {
+        quota.flush();
+    }
+}
+
+/// returns right away without a quota or while some is left, otherwise
+/// sleeps until the exhausted one resets
+pub async fn wait_until_available() {
+    let Some(quota) = QUOTA.get() else {
+        return;
+    };
+    let mut is_first_encounter = true;
+    while let Some((period, wait)) = quota.exceeded_at(quota.clock.now()) {
+        if is_first_encounter {
+            info!("{} quota reached, downloads resume in {}s", period, wait);
+            display::set_quota_paused(true);
+        }
+        is_first_encounter = false;
+        tokio::time::sleep(Duration::from_secs(wait)).await;
+    }
+    if !is_first_encounter {
+        display::set_quota_paused(false);
+    }
+}
+
+#[cfg(test)]
+mod quota_tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(Ok(1024), parse_size("1024"));
+        assert_eq!(Ok(500 << 20), parse_size("500MB"));
+        assert_eq!(Ok(3 << 29), parse_size("1.5GiB"));
+        assert_eq!(Ok(2 << 40), parse_size("2t"));
+        assert!(parse_size("10 parsecs").is_err());
+        assert!(parse_size("-1GB").is_err());
+    }
+
+    #[test]
+    fn daily_and_monthly_limits() {
+        let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+        let mut ledger = Ledger::default();
+        ledger.add(date(2024, 11, 30), None, 300);
+        ledger.add(date(2024, 12, 1), None, 100);
+        ledger.add(date(2024, 12, 31), None, 200);
+        assert_eq!(300, ledger.month(date(2024, 12, 15)));
+        assert_eq!(300, ledger.month(date(2024, 11, 1)));
+
+        let quota = Quota {
+            daily: Some(150),
+            monthly: Some(300),
+            clock: Clock::default(),
+            time_range: None,
+            ledger: Mutex::new(ledger),
+            path: PathBuf::new(),
+            saved: Arc::default(),
+        };
+        let noon = |date: NaiveDate| date.and_hms_opt(12, 0, 0).unwrap();
+        assert_eq!(
+            Some(("Monthly", 12 * 3600)),
+            quota.exceeded_at(noon(date(2024, 12, 31)))
+        );
+        assert_eq!(
+            Some(("Monthly", 12 * 3600)),
+            quota.exceeded_at(noon(date(2024, 11, 30)))
+        );
+
+        let quota = Quota {
+            monthly: None,
+            ..quota
+        };
+        assert_eq!(None, quota.exceeded_at(noon(date(2024, 12, 1))));
+        assert_eq!(
+            Some(("Daily", 12 * 3600)),
+            quota.exceeded_at(noon(date(2024, 12, 31)))
+        );
+    }
+
+    #[test]
+    fn ledger_is_saved_in_batches() {
+        let directory = std::env::temp_dir().join("kavimo-quota-tests");
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join(LEDGER_NAME);
+        let _ = fs::remove_file(&path);
+        let quota = Quota {
+            daily: None,
+            monthly: None,
+            clock: Clock::default(),
+            time_range: None,
+            ledger: Mutex::default(),
+            path: path.clone(),
+            saved: Arc::default(),
+        };
+        let saved_today = || {
+            let ledger: Ledger = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+            ledger.day(quota.clock.now().date())
+        };
+
+        // the first chunk is saved right away, the next ones wait
+        quota.record(100);
+        assert_eq!(100, saved_today());
+        quota.record(100);
+        assert_eq!(100, saved_today());
+        quota.record(FLUSH_EVERY);
+        assert_eq!(FLUSH_EVERY + 200, saved_today());
+
+        quota.record(100);
+        quota.flush();
+        assert_eq!(FLUSH_EVERY + 300, saved_today());
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{error, info};
+
+use crate::display;
+use crate::timer::{Schedule, TimedDownload as _};
+use crate::utils::parse_video;
+use crate::video::Video;
+
+#[derive(Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Finished,
+    Failed,
+    Cancelled,
+}
+
+struct Job {
+    url: String,
+    quality: Option<String>,
+    state: JobState,
+    error: Option<String>,
+    video: Video,
+    cancel: CancellationToken,
+}
+
+#[derive(Serialize)]
+pub struct JobView {
+    id: u64,
+    url: String,
+    quality: Option<String>,
+    state: JobState,
+    paused: bool,
+    error: Option<String>,
+    downloaded_bytes: usize,
+    total_bytes: usize,
+    media_seconds: f64,
+    total_media_seconds: f64,
+}
+
+#[derive(Deserialize)]
+pub struct NewJob {
+    url: String,
+    /// `720`, `best` or `lowest`, first listed quality if omitted
+    quality: Option<String>,
+}
+
+#[derive(Default)]
+struct Jobs {
+    next_id: u64,
+    jobs: BTreeMap<u64, Job>,
+}
+
+#[derive(Clone)]
+struct Server {
+    jobs: Arc<Mutex<Jobs>>,
+    slots: Arc<Semaphore>,
+    time_range: Option<Schedule>,
+    shutdown: CancellationToken,
+    tasks: TaskTracker,
+}
+
+type ApiResult<T> = Result<Json<T>, (StatusCode, String)>;
+
+impl Job {
+    fn view(&self, id: u64) -> JobView {
+        let progress = self.video.progress();
+        JobView {
+            id,
+            url: self.url.clone(),
+            quality: self.quality.clone(),
+            state: self.state,
+            paused: progress.is_paused(),
+            error: self.error.clone(),
+            downloaded_bytes: progress.downloaded(),
+            total_bytes: progress.total(),
+            media_seconds: progress.media_downloaded(),
+            total_media_seconds: progress.media_total(),
+        }
+    }
+}
+
+impl Server {
+    fn set_state(&self, id: u64, state: JobState, error: Option<String>) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.jobs.get_mut(&id) {
+            // a cancelled job must not be revived by its task finishing late
+            if job.state != JobState::Cancelled {
+                job.state = state;
+                job.error = error;
+            }
+        }
+    }
+
+    async fn run_job(self, id: u64, mut video: Video, cancel: CancellationToken) {
+        // jobs stay queued instead of holding a slot while the timer is out of range
+        let permit = tokio::select! {
+            _ = cancel.cancelled() => None,
+            permit = async {
+                self.time_range.wait_until_in_range().await;
+                self.slots.clone().acquire_owned().await
+            } => permit.ok(),
+        };
+        let Some(_permit) = permit else {
+            display::job_finished();
+            return;
+        };
+        self.set_state(id, JobState::Running, None);
+        if let Some(timer) = &self.time_range {
+            video.set_time_range(timer.clone()).await;
+        }
+        // Box<dyn Error> is not Send, so only the message leaves the download
+        let result = video.download(true, cancel).await.map_err(|err| err.to_string());
+        display::job_finished();
+        match result {
+            Ok(_) => self.set_state(id, JobState::Finished, None),
+            Err(err) => {
+                error!("Job {} failed: '{}'", id, &err);
+                self.set_state(id, JobState::Failed, Some(err));
+            }
+        }
+    }
+}
+
+/// serves the job control API on localhost until `shutdown` is cancelled,
+/// running jobs are cancelled too and waited for before returning
+pub async fn serve(
+    port: u16,
+    time_range: Option<Schedule>,
+    parallel: usize,
+    shutdown: CancellationToken,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let tasks = TaskTracker::new();
+    let server = Server {
+        jobs: Arc::new(Mutex::new(Jobs::default())),
+        slots: Arc::new(Semaphore::new(parallel.max(1))),
+        time_range: time_range.clone(),
+        shutdown: shutdown.clone(),
+        tasks: tasks.clone(),
+    };
+
+    let app = Router::new()
+        .route("/jobs", get(list_jobs).post(create_job))
+        .route("/jobs/:id", get(get_job).delete(cancel_job))
+        .route("/jobs/:id/pause", post(pause_job))
+        .route("/jobs/:id/resume", post(resume_job))
+        .with_state(server);
+
+    if parallel > 1 {
+        display::start_batch(0, time_range.is_some());
+    }
+
+    let address = SocketAddr::from(([127, 0, 0, 1], port));
+    info!("Listening on http://{}", address);
+    axum::Server::try_bind(&address)?
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(shutdown.cancelled())
+        .await?;
+
+    tasks.close();
+    tasks.wait().await;
+    display::finish_batch();
+
+    Ok(())
+}
+
+fn not_found(id: u64) -> (StatusCode, String) {
+    (StatusCode::NOT_FOUND, format!("job {} does not exist", id))
+}
+
+async fn create_job(State(server): State<Server>, Json(new_job): Json<NewJob>) -> ApiResult<JobView> {
+    let line = match &new_job.quality {
+        Some(quality) => format!("{} {}", new_job.url, quality),
+        None => new_job.url.clone(),
+    };
+    let video = parse_video(&line)
+        .map_err(|_| (StatusCode::BAD_REQUEST, format!("'{}' is not a valid link", new_job.url)))?;
+
+    let mut jobs = server.jobs.lock().unwrap();
+    let id = jobs.next_id;
+    jobs.next_id += 1;
+
+    display::job_added();
+    let cancel = server.shutdown.child_token();
+    server
+        .tasks
+        .spawn(server.clone().run_job(id, video.clone(), cancel.clone()));
+    let job = Job {
+        url: new_job.url,
+        quality: new_job.quality,
+        state: JobState::Queued,
+        error: None,
+        video,
+        cancel,
+    };
+    let view = job.view(id);
+    jobs.jobs.insert(id, job);
+
+    Ok(Json(view))
+}
+
+async fn list_jobs(State(server): State<Server>) -> Json<Vec<JobView>> {
+    let jobs = server.jobs.lock().unwrap();
+    Json(jobs.jobs.iter().map(|(id, job)| job.view(*id)).collect())
+}
+
+async fn get_job(State(server): State<Server>, Path(id): Path<u64>) -> ApiResult<JobView> {
+    let jobs = server.jobs.lock().unwrap();
+    let job = jobs.jobs.get(&id).ok_or(not_found(id))?;
+    Ok(Json(job.view(id)))
+}
+
+async fn cancel_job(State(server): State<Server>, Path(id): Path<u64>) -> ApiResult<JobView> {
+    let mut jobs = server.jobs.lock().unwrap();
+    let job = jobs.jobs.get_mut(&id).ok_or(not_found(id))?;
+    if matches!(job.state, JobState::Queued | JobState::Running) {
+        job.cancel.cancel();
+        job.state = JobState::Cancelled;
+    }
+    Ok(Json(job.view(id)))
+}
+
+async fn pause_job(State(server): State<Server>, Path(id): Path<u64>) -> ApiResult<JobView> {
+    let jobs = server.jobs.lock().unwrap();
+    let job = jobs.jobs.get(&id).ok_or(not_found(id))?;
+    job.video.progress().pause();
+    Ok(Json(job.view(id)))
+}
+
+async fn resume_job(State(server): State<Server>, Path(id): Path<u64>) -> ApiResult<JobView> {
+    let jobs = server.jobs.lock().unwrap();
+    let job = jobs.jobs.get(&id).ok_or(not_found(id))?;
+    job.video.progress().resume();
+    Ok(Json(job.view(id)))
+}
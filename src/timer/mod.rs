@@ -1,4 +1,11 @@
-use chrono::{NaiveTime, Timelike};
+use chrono::{Datelike, NaiveDateTime, NaiveTime, Timelike, Weekday};
+use chrono_tz::Tz;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::info;
+
+use crate::display;
 
 const SECONDS_IN_DAY: u32 = 86_400;
 
@@ -10,12 +17,43 @@ pub struct TimeRange {
 }
 
 
+/// a daily range plus the days of week it starts on, a range passing
+/// midnight belongs to the day it started on
+#[derive(Clone)]
+pub struct TimeWindow {
+    /// indexed by days from monday
+    days: [bool; 7],
+    range: TimeRange,
+}
+
+/// the timer is in range while any of its windows is
+#[derive(Clone)]
+pub struct Schedule {
+    windows: Vec<TimeWindow>,
+    /// batches only start videos expected to finish before the window closes
+    whole_videos: bool,
+    clock: Clock,
+}
+
+/// wall clock in the system timezone unless one is given
+#[derive(Clone, Copy, Default)]
+pub struct Clock {
+    timezone: Option<Tz>,
+}
+
+/// `HH:MM:SS` or `HH:MM` as seconds from midnight
+pub fn parse_clock(input: &str) -> Result<u32, Box<dyn std::error::Error>> {
+    let time = NaiveTime::parse_from_str(input, "%H:%M:%S")
+        .or_else(|_| NaiveTime::parse_from_str(input, "%H:%M"))?;
+    Ok(time.num_seconds_from_midnight())
+}
+
 pub fn parse_time(input: &str) -> Result<TimeRange, Box<dyn std::error::Error>> {
 
     let mut sp = input.split('-');
 
-    let start = NaiveTime::parse_from_str(sp.next().ok_or("")?, "%H:%M:%S")?.num_seconds_from_midnight();
-    let end = NaiveTime::parse_from_str(sp.next().ok_or("")?, "%H:%M:%S")?.num_seconds_from_midnight();
+    let start = parse_clock(sp.next().ok_or("")?)?;
+    let end = parse_clock(sp.next().ok_or("")?)?;
 
     let time_range = TimeRange {
         start,
@@ -25,42 +63,221 @@ pub fn parse_time(input: &str) -> Result<TimeRange, Box<dyn std::error::Error>>
     Ok(time_range)
 }
 
+/// parses windows like `Mon-Fri 01:00-07:00, Sat,Sun 00:00-12:00`, a range
+/// without days applies to every day so a plain `--timer 02:00:00-08:00:00`
+/// keeps working, lines starting with `#` are ignored to allow config files
+pub fn parse_schedule(input: &str) -> Result<Schedule, Box<dyn std::error::Error>> {
+    let mut windows = Vec::new();
+    let mut days = [false; 7];
+    let mut has_days = false;
+
+    let tokens = input
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .flat_map(|line| line.split_whitespace());
+    for token in tokens {
+        let token = token.trim_matches(|c| c == ',' || c == ';');
+        if token.contains(':') {
+            if !has_days {
+                days = [true; 7];
+            }
+            windows.push(TimeWindow {
+                days,
+                range: parse_time(token)?,
+            });
+            days = [false; 7];
+            has_days = false;
+            continue;
+        }
+        for item in token.split(',').filter(|x| !x.is_empty()) {
+            let mut bounds = item.split('-');
+            let first = parse_weekday(bounds.next().ok_or("")?)?;
+            let last = match bounds.next() {
+                Some(last) => parse_weekday(last)?,
+                None => first,
+            };
+            let mut day = first;
+            loop {
+                days[day] = true;
+                if day == last {
+                    break;
+                }
+                day = (day + 1) % 7;
+            }
+            has_days = true;
+        }
+    }
+
+    if has_days {
+        return Err("days without a time range at the end of timer".into());
+    }
+    if windows.is_empty() {
+        return Err("timer has no time range".into());
+    }
+    Ok(Schedule {
+        windows,
+        whole_videos: false,
+        clock: Clock::default(),
+    })
+}
+
+/// IANA timezone name, e.g. `Asia/Tehran` or `UTC`
+pub fn parse_timezone(input: &str) -> Result<Tz, Box<dyn std::error::Error>> {
+    Ok(input.parse::<Tz>()?)
+}
+
+fn parse_weekday(input: &str) -> Result<usize, Box<dyn std::error::Error>> {
+    let weekday = input
+        .parse::<Weekday>()
+        .map_err(|_| format!("'{}' is not a day of week", input))?;
+    Ok(weekday.num_days_from_monday() as usize)
+}
+
 pub trait TimedDownload {
-    fn should_coutinue(&self);
-    fn is_in_range(&self) -> bool;
+    fn wait_until_in_range(&self) -> impl Future<Output = ()> + Send + '_;
 }
 
-impl TimedDownload for Option<TimeRange> {
-    fn should_coutinue(&self) {
+impl TimedDownload for Option<Schedule> {
+    /// sleeps until the window opens without blocking the runtime, returns
+    /// right away when there is no timer or it is already in range
+    async fn wait_until_in_range(&self) {
+        let Some(timer) = self else {
+            return;
+        };
         let mut is_first_encounter = true;
-        while !self.is_in_range() {
+        loop {
+            let (weekday, now) = timer.now();
+            let wait = timer.seconds_until_open(weekday, now);
+            if wait == 0 {
+                break;
+            }
             if is_first_encounter {
-                println!("[INFO] Timer is out of range waiting for timer to get in range");
+                info!("Timer is out of range, window opens in {}s", wait);
+                display::set_timer_paused(true);
             }
             is_first_encounter = false;
-            std::thread::sleep(std::time::Duration::from_secs(10));
+            // checked again after waking up in case the clock was changed meanwhile
+            let deadline = Instant::now() + Duration::from_secs(wait as u64);
+            tokio::time::sleep_until(deadline).await;
+        }
+        if !is_first_encounter {
+            display::set_timer_paused(false);
+        }
+    }
+}
+
+/// days from monday and seconds from midnight
+fn day_and_time<T: Datelike + Timelike>(now: T) -> (usize, u32) {
+    (
+        now.weekday().num_days_from_monday() as usize,
+        now.num_seconds_from_midnight(),
+    )
+}
+
+impl Clock {
+    pub fn new(timezone: Option<Tz>) -> Self {
+        Self { timezone }
+    }
+
+    pub fn now(&self) -> NaiveDateTime {
+        match self.timezone {
+            Some(timezone) => chrono::Utc::now().with_timezone(&timezone).naive_local(),
+            None => chrono::Local::now().naive_local(),
         }
     }
+}
 
-    fn is_in_range(&self) -> bool {
-        match self {
-            Some(timer) => {
-                if timer.now_in_range() {
-                    return true;
-                } else {
-                    return false;
+impl Schedule {
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    fn now(&self) -> (usize, u32) {
+        day_and_time(self.clock.now())
+    }
+
+    /// start of the open window, `None` while out of range
+    pub fn window_start(&self) -> Option<NaiveDateTime> {
+        let now = self.clock.now();
+        let (weekday, seconds) = day_and_time(now);
+        let (left, length) = self.window_left_at(weekday, seconds)?;
+        Some(now - chrono::Duration::seconds((length - left) as i64))
+    }
+
+    pub fn set_whole_videos(&mut self, whole_videos: bool) {
+        self.whole_videos = whole_videos;
+    }
+
+    pub fn whole_videos(&self) -> bool {
+        self.whole_videos
+    }
+
+    /// seconds left of the open window and its whole length, `None` while
+    /// out of range
+    pub fn window_left(&self) -> Option<(u32, u32)> {
+        let (weekday, now) = self.now();
+        self.window_left_at(weekday, now)
+    }
+
+    fn window_left_at(&self, weekday: usize, now: u32) -> Option<(u32, u32)> {
+        self.windows
+            .iter()
+            .filter(|window| window.contains(weekday, now))
+            .map(|window| (window.range.seconds_until_end(now), window.range.length()))
+            .max()
+    }
+
+    fn is_open(&self, weekday: usize, now: u32) -> bool {
+        self.windows.iter().any(|window| window.contains(weekday, now))
+    }
+
+    /// zero while open, otherwise seconds until the next window starts
+    fn seconds_until_open(&self, weekday: usize, now: u32) -> u32 {
+        if self.is_open(weekday, now) {
+            return 0;
+        }
+        let mut wait = None;
+        for window in &self.windows {
+            for offset in 0..=7 {
+                if !window.days[(weekday + offset) % 7] {
+                    continue;
+                }
+                let start = offset as i64 * SECONDS_IN_DAY as i64 + window.range.start as i64;
+                // the range itself starts one second after `start`
+                let until = start - now as i64 + 1;
+                if until > 0 {
+                    wait = Some(wait.map_or(until, |x: i64| x.min(until)));
+                    break;
                 }
             }
-            None => true
         }
+        wait.unwrap_or(SECONDS_IN_DAY as i64) as u32
+    }
+}
+
+impl TimeWindow {
+    fn contains(&self, weekday: usize, now: u32) -> bool {
+        if !self.range.is_in_time_range(now) {
+            return false;
+        }
+        let started_yesterday = self.range.start > self.range.end && now < self.range.end;
+        let start_day = if started_yesterday {
+            (weekday + 6) % 7
+        } else {
+            weekday
+        };
+        self.days[start_day]
     }
 }
 
 impl TimeRange {
 
-    fn now_in_range(&self) -> bool {
-        let now = chrono::Local::now().time().num_seconds_from_midnight();
-        self.is_in_time_range(now)
+    fn length(&self) -> u32 {
+        (self.end + SECONDS_IN_DAY - self.start) % SECONDS_IN_DAY
+    }
+
+    fn seconds_until_end(&self, now: u32) -> u32 {
+        (self.end + SECONDS_IN_DAY - now) % SECONDS_IN_DAY
     }
 
     fn is_in_time_range(&self, mut now: u32) -> bool {
@@ -113,9 +330,72 @@ mod timer_tests {
 
         Ok(())
     }
-}
 
+    #[test]
+    fn weekday_windows() -> Result<(), Box<dyn std::error::Error>> {
+        let hs = |h, m, s| NaiveTime::from_hms_opt(h, m, s).unwrap().num_seconds_from_midnight();
+        let (mon, fri, sat, sun) = (0, 4, 5, 6);
+        let schedule = parse_schedule("Mon-Fri 01:00-07:00, Sat,Sun 00:00-12:00")?;
+
+        assert!(schedule.is_open(mon, hs(3, 0, 0)));
+        assert!(!schedule.is_open(mon, hs(9, 0, 0)));
+        assert!(schedule.is_open(sun, hs(9, 0, 0)));
+        assert!(!schedule.is_open(sun, hs(13, 0, 0)));
+
+        // friday night window runs into saturday morning
+        let friday_night = parse_schedule("# off-peak\nFri 22:00-02:00")?;
+        assert!(friday_night.is_open(fri, hs(23, 0, 0)));
+        assert!(friday_night.is_open(sat, hs(1, 30, 0)));
+        assert!(!friday_night.is_open(sun, hs(1, 30, 0)));
+        assert!(!friday_night.is_open(mon, hs(23, 0, 0)));
 
+        let every_day = parse_schedule("02:00:00-08:00:00")?;
+        assert!(every_day.is_open(sat, hs(3, 0, 0)));
 
+        assert!(parse_schedule("Mon-Fri").is_err());
+        assert!(parse_schedule("Mon-Fry 01:00-02:00").is_err());
+        assert!(parse_schedule("# only a comment").is_err());
 
+        Ok(())
+    }
 
+    #[test]
+    fn time_until_open() -> Result<(), Box<dyn std::error::Error>> {
+        let hs = |h, m, s| NaiveTime::from_hms_opt(h, m, s).unwrap().num_seconds_from_midnight();
+        let (mon, fri, sat) = (0, 4, 5);
+
+        let nightly = parse_schedule("22:00:00-04:00:00")?;
+        assert_eq!(0, nightly.seconds_until_open(mon, hs(23, 0, 0)));
+        assert_eq!(0, nightly.seconds_until_open(mon, hs(3, 0, 0)));
+        assert_eq!(hs(2, 0, 1), nightly.seconds_until_open(mon, hs(20, 0, 0)));
+        assert_eq!(hs(18, 0, 1), nightly.seconds_until_open(mon, hs(4, 0, 0)));
+        assert_eq!(1, nightly.seconds_until_open(mon, hs(22, 0, 0)));
+
+        // after the friday window the next one is on monday
+        let weekdays = parse_schedule("Mon-Fri 01:00-07:00")?;
+        assert_eq!(hs(18, 0, 1) + 2 * SECONDS_IN_DAY, weekdays.seconds_until_open(fri, hs(7, 0, 0)));
+        assert_eq!(hs(1, 0, 1) + 2 * SECONDS_IN_DAY, weekdays.seconds_until_open(sat, hs(0, 0, 0)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn timezones() {
+        assert!(parse_timezone("Asia/Tehran").is_ok());
+        assert!(parse_timezone("UTC").is_ok());
+        assert!(parse_timezone("Tehran").is_err());
+    }
+
+    #[test]
+    fn window_left() -> Result<(), Box<dyn std::error::Error>> {
+        let hs = |h, m, s| NaiveTime::from_hms_opt(h, m, s).unwrap().num_seconds_from_midnight();
+        let mon = 0;
+
+        let schedule = parse_schedule("22:00:00-04:00:00, 23:00-01:00")?;
+        assert_eq!(Some((hs(5, 0, 0), hs(6, 0, 0))), schedule.window_left_at(mon, hs(23, 0, 0)));
+        assert_eq!(Some((hs(0, 30, 0), hs(6, 0, 0))), schedule.window_left_at(mon, hs(3, 30, 0)));
+        assert_eq!(None, schedule.window_left_at(mon, hs(12, 0, 0)));
+
+        Ok(())
+    }
+}
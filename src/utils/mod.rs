@@ -1,19 +1,153 @@
+use std::fs;
+use std::path::Path;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
 use url::{Host, Url};
-use crate::video::Video;
+use crate::http::{self, Identity};
+use crate::video::{QualityPolicy, Video};
 
 
 
+/// `<video> [quality] [option=value ...]` where the options are `referer`,
+/// `user-agent`, `header` and `cookies`, values with spaces go in double quotes
 pub fn parse_video(input: &str) -> Result<Video, Box<dyn std::error::Error>> {
-    let mut splitter = input.split(' ');
+    let mut splitter = split_line(input).into_iter();
     let url_text = splitter.next().ok_or("Cannot get link value from text line")?;
     let url = Url::parse(&url_text)?;
     let video_id = url.path()[1..].split('/').next().ok_or("no video Id found")?;
     let host = url.host().ok_or("no video host found")?;
-    let quality = splitter.next().map(|x| x.to_owned());
+    let (quality, identity) = parse_options(splitter)?;
     if let Host::Domain(video_host) = host {
-        return Ok(Video::new(video_id.to_string(), video_host.to_string(), quality));
+        return Ok(Video::new(video_id.to_string(), video_host.to_string(), quality, identity)?);
     }
     Err("Cannot get video host".into())
 }
 
+/// quality and options that follow the link of a line
+fn parse_options(
+    tokens: impl Iterator<Item = String>,
+) -> Result<(Option<QualityPolicy>, Identity), Box<dyn std::error::Error>> {
+    let mut quality = None;
+    let mut identity = Identity::default();
+    for token in tokens {
+        match token.split_once('=') {
+            Some(("referer", value)) => identity.referer = Some(value.to_string()),
+            Some(("user-agent", value)) => identity.user_agent = Some(value.to_string()),
+            Some(("header", value)) => {
+                let (name, value) = http::parse_header(value)?;
+                identity.headers.append(name, value);
+            }
+            Some(("cookies", path)) => identity.cookies = Some(http::load_video_cookies(path)?),
+            Some((option, _)) => return Err(format!("unknown option '{}'", option).into()),
+            None if quality.is_none() => quality = Some(QualityPolicy::from(token.as_str())),
+            None => return Err(format!("unexpected '{}'", token).into()),
+        }
+    }
+    Ok((quality, identity))
+}
+
+/// splits on whitespace except inside double quotes, the quotes are removed
+fn split_line(input: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut is_quoted = false;
+    for char in input.chars() {
+        match char {
+            '"' => is_quoted = !is_quoted,
+            x if x.is_whitespace() && !is_quoted => {
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+            }
+            x => token.push(x),
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    tokens
+}
+
+/// writes to `<name>.tmp` next to the file first and renames it over the file,
+/// so an interrupted write never leaves a half written file behind
+pub fn write_atomically(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+    fs::write(&temp_path, bytes)?;
+    fs::rename(&temp_path, path)
+}
+
+/// cancels `token` on the first Ctrl-C and quits right away on the second one
+pub async fn cancel_on_ctrl_c(token: CancellationToken) {
+    if tokio::signal::ctrl_c().await.is_err() {
+        return;
+    }
+    info!("Stopping, waiting for parts in flight (press Ctrl-C again to quit now)");
+    token.cancel();
+    if tokio::signal::ctrl_c().await.is_ok() {
+        std::process::exit(130);
+    }
+}
+
+#[cfg(test)]
+mod utils_tests {
+    use super::*;
+    use reqwest::cookie::CookieStore as _;
 
+    fn options(line: &str) -> Result<(Option<QualityPolicy>, Identity), Box<dyn std::error::Error>> {
+        parse_options(split_line(line).into_iter())
+    }
+
+    #[test]
+    fn quoted_values() {
+        assert_eq!(
+            vec!["https://a.com/x/embed", "720", "user-agent=Mozilla/5.0 (X11; Linux)"],
+            split_line("https://a.com/x/embed  720\tuser-agent=\"Mozilla/5.0 (X11; Linux)\"")
+        );
+        // quotes may cover the whole pair, an unclosed one runs to the end of the line
+        assert_eq!(vec!["header=X-A: 1"], split_line("\"header=X-A: 1\""));
+        assert_eq!(vec!["referer=a b"], split_line("referer=\"a b"));
+        assert!(split_line("   ").is_empty());
+    }
+
+    #[test]
+    fn line_options() -> Result<(), Box<dyn std::error::Error>> {
+        let (quality, identity) = options(
+            "best referer=https://lms.example.com/c/1 user-agent=\"Firefox 1\" \
+            header=\"X-Token: abc\" header=\"X-Token: def\"",
+        )?;
+        assert_eq!(Some(QualityPolicy::Highest), quality);
+        assert_eq!(Some("https://lms.example.com/c/1"), identity.referer.as_deref());
+        assert_eq!(Some("Firefox 1"), identity.user_agent.as_deref());
+        assert_eq!(2, identity.headers.get_all("x-token").iter().count());
+        assert!(identity.cookies.is_none());
+
+        let (quality, _) = options("referer=https://a.com")?;
+        assert_eq!(None, quality);
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_options() {
+        assert!(options("proxy=http://127.0.0.1:8080").is_err());
+        assert!(options("=value").is_err());
+        assert!(options("header=no-colon").is_err());
+        assert!(options("720 480").is_err());
+        assert!(options("cookies=/nonexistent/cookies.txt").is_err());
+    }
+
+    #[test]
+    fn cookies_stay_with_their_video() -> Result<(), Box<dyn std::error::Error>> {
+        let path = std::env::temp_dir().join("kavimo-utils-cookies.txt");
+        std::fs::write(&path, "stream.example.com\tFALSE\t/\tFALSE\t0\tsession\tabc\n")?;
+        let line = format!("cookies=\"{}\"", path.display());
+        let (_, identity) = options(&line)?;
+        let _ = std::fs::remove_file(&path);
+
+        let url = "https://stream.example.com/".parse()?;
+        let jar = identity.cookies.ok_or("no cookies loaded")?;
+        assert_eq!("session=abc", jar.cookies(&url).ok_or("cookie missing")?);
+        Ok(())
+    }
+}
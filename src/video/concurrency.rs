@@ -0,0 +1,241 @@
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{AcquireError, OwnedSemaphorePermit, Semaphore};
+
+const DEFAULT_LEVEL: usize = 10;
+const ADAPTIVE_START: usize = 4;
+const ADAPTIVE_MAX: usize = 32;
+/// throughput has to grow by this factor for another connection to be added
+const GROWTH: f64 = 1.05;
+const MAX_ERROR_RATE: f64 = 0.1;
+/// latency this many times the best one seen counts as congestion
+const MAX_LATENCY_FACTOR: u32 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConcurrencyMode {
+    Fixed(usize),
+    Adaptive,
+}
+
+static MODE: OnceLock<ConcurrencyMode> = OnceLock::new();
+
+pub fn set_concurrency(mode: ConcurrencyMode) {
+    let _ = MODE.set(mode);
+}
+
+/// a number of parallel parts or `auto`
+pub fn parse_concurrency(input: &str) -> Result<ConcurrencyMode, String> {
+    if input.eq_ignore_ascii_case("auto") {
+        return Ok(ConcurrencyMode::Adaptive);
+    }
+    match input.parse::<usize>() {
+        Ok(level) if level > 0 => Ok(ConcurrencyMode::Fixed(level)),
+        _ => Err(format!("'{}' is neither a positive number nor auto", input)),
+    }
+}
+
+/// result of a part request as seen by the controller
+pub enum Outcome {
+    /// `latency` is the time until the response headers arrived
+    Success { bytes: usize, latency: Duration },
+    /// request errors, 429 and 5xx responses
+    Failure,
+}
+
+struct Window {
+    started: Instant,
+    bytes: usize,
+    successes: u32,
+    failures: u32,
+    latency: Duration,
+}
+
+struct State {
+    level: usize,
+    /// permits to drop instead of returning after a decrease
+    shrink: usize,
+    window: Window,
+    last_throughput: f64,
+    best_latency: Option<Duration>,
+}
+
+/// parts of a video downloaded at the same time, the adaptive mode adds a
+/// connection while throughput keeps rising and halves them when errors or
+/// latency climb
+pub struct Concurrency {
+    semaphore: Arc<Semaphore>,
+    is_adaptive: bool,
+    state: Mutex<State>,
+}
+
+/// permit of a single part, it reports the outcome of its requests
+pub struct Slot {
+    permit: Option<OwnedSemaphorePermit>,
+    owner: Arc<Concurrency>,
+}
+
+impl Window {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            bytes: 0,
+            successes: 0,
+            failures: 0,
+            latency: Duration::ZERO,
+        }
+    }
+}
+
+impl Concurrency {
+    pub fn new() -> Self {
+        let mode = MODE
+            .get()
+            .copied()
+            .unwrap_or(ConcurrencyMode::Fixed(DEFAULT_LEVEL));
+        Self::with_mode(mode)
+    }
+
+    fn with_mode(mode: ConcurrencyMode) -> Self {
+        let (level, is_adaptive) = match mode {
+            ConcurrencyMode::Fixed(level) => (level, false),
+            ConcurrencyMode::Adaptive => (ADAPTIVE_START, true),
+        };
+        Self {
+            semaphore: Arc::new(Semaphore::new(level)),
+            is_adaptive,
+            state: Mutex::new(State {
+                level,
+                shrink: 0,
+                window: Window::new(),
+                last_throughput: 0.0,
+                best_latency: None,
+            }),
+        }
+    }
+
+    pub fn is_adaptive(&self) -> bool {
+        self.is_adaptive
+    }
+
+    pub fn level(&self) -> usize {
+        self.state.lock().unwrap().level
+    }
+
+    pub async fn acquire(self: &Arc<Self>) -> Result<Slot, AcquireError> {
+        let permit = self.semaphore.clone().acquire_owned().await?;
+        Ok(Slot {
+            permit: Some(permit),
+            owner: self.clone(),
+        })
+    }
+
+    /// returns the new level when it changed
+    fn record(&self, outcome: Outcome) -> Option<usize> {
+        if !self.is_adaptive {
+            return None;
+        }
+        let mut state = self.state.lock().unwrap();
+        match outcome {
+            Outcome::Success { bytes, latency } => {
+                state.window.bytes += bytes;
+                state.window.successes += 1;
+                state.window.latency += latency;
+            }
+            Outcome::Failure => state.window.failures += 1,
+        }
+        let samples = state.window.successes + state.window.failures;
+        if (samples as usize) < state.level.max(ADAPTIVE_START) {
+            return None;
+        }
+
+        let window = std::mem::replace(&mut state.window, Window::new());
+        let elapsed = window.started.elapsed().as_secs_f64().max(0.001);
+        let throughput = window.bytes as f64 / elapsed;
+        let error_rate = window.failures as f64 / samples as f64;
+        let latency = window.latency.checked_div(window.successes);
+        let is_slow = match (latency, state.best_latency) {
+            (Some(latency), Some(best)) => latency > best * MAX_LATENCY_FACTOR,
+            _ => false,
+        };
+        if let Some(latency) = latency {
+            state.best_latency = Some(state.best_latency.map_or(latency, |x| x.min(latency)));
+        }
+        let last_throughput = std::mem::replace(&mut state.last_throughput, throughput);
+
+        let level = state.level;
+        if error_rate > MAX_ERROR_RATE || is_slow {
+            let lower = (level / 2).max(1);
+            // idle permits go right away, the rest when their parts finish
+            for _ in lower..level {
+                match self.semaphore.try_acquire() {
+                    Ok(permit) => permit.forget(),
+                    Err(_) => state.shrink += 1,
+                }
+            }
+            state.level = lower;
+        } else if throughput > last_throughput * GROWTH && level < ADAPTIVE_MAX {
+            if state.shrink > 0 {
+                state.shrink -= 1;
+            } else {
+                self.semaphore.add_permits(1);
+            }
+            state.level += 1;
+        }
+        (state.level != level).then_some(state.level)
+    }
+}
+
+impl Slot {
+    pub fn record(&self, outcome: Outcome) -> Option<usize> {
+        self.owner.record(outcome)
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut state = self.owner.state.lock().unwrap();
+        if state.shrink > 0 {
+            state.shrink -= 1;
+            if let Some(permit) = self.permit.take() {
+                permit.forget();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod concurrency_tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn increase_and_back_off() -> Result<(), AcquireError> {
+        let concurrency = Arc::new(Concurrency::with_mode(ConcurrencyMode::Adaptive));
+        let success = || Outcome::Success {
+            bytes: 1 << 20,
+            latency: Duration::from_millis(50),
+        };
+
+        // the first window always grows from zero throughput
+        let slot = concurrency.acquire().await?;
+        let changes: Vec<_> = (0..ADAPTIVE_START)
+            .filter_map(|_| slot.record(success()))
+            .collect();
+        assert_eq!(vec![ADAPTIVE_START + 1], changes);
+        assert_eq!(ADAPTIVE_START, concurrency.semaphore.available_permits());
+
+        // errors halve the level, idle permits are taken away right away
+        let changes: Vec<_> = (0..=ADAPTIVE_START)
+            .filter_map(|_| slot.record(Outcome::Failure))
+            .collect();
+        assert_eq!(vec![2], changes);
+        assert_eq!(1, concurrency.semaphore.available_permits());
+        drop(slot);
+        assert_eq!(2, concurrency.semaphore.available_permits());
+
+        assert_eq!(Ok(ConcurrencyMode::Adaptive), parse_concurrency("auto"));
+        assert_eq!(Ok(ConcurrencyMode::Fixed(6)), parse_concurrency("6"));
+        assert!(parse_concurrency("0").is_err());
+
+        Ok(())
+    }
+}
@@ -1,6 +1,67 @@
 use libc;
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
 
 #[link(name="convert", kind="static")]
 extern "C" {
     pub fn convert_video_from_mpeg_to_mp4(input: *const libc::c_char, output: *const libc::c_char) -> libc::c_int;
+    fn extract_audio(input: *const libc::c_char, output: *const libc::c_char, title: *const libc::c_char) -> libc::c_int;
+    fn trim_mpeg(input: *const libc::c_char, output: *const libc::c_char, start: libc::c_double, end: libc::c_double, cut_start: *mut libc::c_double) -> libc::c_int;
+    fn mux_tracks(video: *const libc::c_char, tracks: *const *const libc::c_char, languages: *const *const libc::c_char, track_count: libc::c_int, output: *const libc::c_char) -> libc::c_int;
+}
+
+/// muxes the mpeg video with extra audio and subtitle tracks, each one tagged with its language
+pub fn mux_video_with_tracks(video: &Path, tracks: &[(PathBuf, String)], output: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let c_string = |path: &Path| -> Result<CString, Box<dyn std::error::Error>> {
+        Ok(CString::new(path.to_str().ok_or("Cannot convert PathBuf to &str")?)?)
+    };
+    let video = c_string(video)?;
+    let output = CString::new(output)?;
+    let mut track_files = Vec::new();
+    let mut languages = Vec::new();
+    for (path, language) in tracks {
+        track_files.push(c_string(path)?);
+        languages.push(CString::new(language.as_str())?);
+    }
+    let track_pointers: Vec<_> = track_files.iter().map(|x| x.as_ptr()).collect();
+    let language_pointers: Vec<_> = languages.iter().map(|x| x.as_ptr()).collect();
+
+    let result = unsafe {
+        mux_tracks(video.as_ptr(), track_pointers.as_ptr(), language_pointers.as_ptr(), tracks.len() as libc::c_int, output.as_ptr())
+    };
+    if result != 0 {
+        return Err("Cannot mux the video with its audio and subtitle tracks".into());
+    }
+    Ok(())
+}
+
+/// copies the aac stream of the mpeg video into an m4a file titled after the video
+pub fn extract_audio_to_m4a(video: &Path, output: &str, title: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let video = CString::new(video.to_str().ok_or("Cannot convert PathBuf to &str")?)?;
+    let output = CString::new(output)?;
+    let title = CString::new(title)?;
+
+    let result = unsafe {
+        extract_audio(video.as_ptr(), output.as_ptr(), title.as_ptr())
+    };
+    if result != 0 {
+        return Err("Cannot extract the audio of the video".into());
+    }
+    Ok(())
+}
+
+/// copies the seconds between start and end of an mpeg file into another one, returns the
+/// second the copy starts at which is the last keyframe before start
+pub fn trim(input: &Path, output: &Path, start: f64, end: f64) -> Result<f64, Box<dyn std::error::Error>> {
+    let input = CString::new(input.to_str().ok_or("Cannot convert PathBuf to &str")?)?;
+    let output = CString::new(output.to_str().ok_or("Cannot convert PathBuf to &str")?)?;
+    let mut cut_start = 0.0;
+
+    let result = unsafe {
+        trim_mpeg(input.as_ptr(), output.as_ptr(), start, end, &mut cut_start)
+    };
+    if result != 0 {
+        return Err("Cannot cut the section out of the video".into());
+    }
+    Ok(cut_start)
 }
@@ -0,0 +1,125 @@
+use aes::cipher::{
+    block_padding::{NoPadding, Pkcs7},
+    BlockDecryptMut, KeyIvInit,
+};
+use std::sync::Arc;
+
+const BLOCK_SIZE: usize = 16;
+
+type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
+
+/// key and iv of an AES-128 encrypted segment
+#[derive(Clone)]
+pub struct SegmentKey {
+    pub key: Arc<Vec<u8>>,
+    pub iv: Vec<u8>,
+}
+
+/// AES-128-CBC decryption of a segment while its body arrives, only the last
+/// block is held back since the PKCS7 padding can be removed at the end only
+pub struct SegmentDecryptor {
+    /// `None` for unencrypted segments, which pass through as they are
+    key: Option<Arc<Vec<u8>>>,
+    /// last ciphertext block that was decrypted, the iv of the next one
+    iv: Vec<u8>,
+    pending: Vec<u8>,
+}
+
+impl SegmentDecryptor {
+    pub fn new(key: Option<SegmentKey>) -> Self {
+        match key {
+            Some(SegmentKey { key, iv }) => Self::resume(Some(key), iv, Vec::new()),
+            None => Self::resume(None, vec![0; BLOCK_SIZE], Vec::new()),
+        }
+    }
+
+    /// continues from the state an earlier attempt left behind
+    pub fn resume(key: Option<Arc<Vec<u8>>>, iv: Vec<u8>, pending: Vec<u8>) -> Self {
+        Self { key, iv, pending }
+    }
+
+    pub fn iv(&self) -> &[u8] {
+        &self.iv
+    }
+
+    /// ciphertext received but not decrypted yet
+    pub fn pending(&self) -> &[u8] {
+        &self.pending
+    }
+
+    /// decrypts every complete block of the chunk except the last one
+    pub fn update(&mut self, chunk: &[u8]) -> Vec<u8> {
+        let Some(key) = &self.key else {
+            return chunk.to_vec();
+        };
+        self.pending.extend_from_slice(chunk);
+        let ready = self.pending.len().saturating_sub(1) / BLOCK_SIZE * BLOCK_SIZE;
+        if ready == 0 {
+            return Vec::new();
+        }
+        let mut blocks: Vec<u8> = self.pending.drain(..ready).collect();
+        let next_iv = blocks[ready - BLOCK_SIZE..].to_vec();
+        let cipher = Aes128CbcDec::new(key.as_slice().into(), self.iv.as_slice().into());
+        // whole blocks only, so this cannot fail
+        cipher
+            .decrypt_padded_mut::<NoPadding>(&mut blocks)
+            .expect("whole blocks");
+        self.iv = next_iv;
+        blocks
+    }
+
+    /// decrypts the held back block and removes the padding, `None` when the
+    /// body did not end on a block with valid padding
+    pub fn finish(&mut self) -> Option<Vec<u8>> {
+        let mut last = std::mem::take(&mut self.pending);
+        let Some(key) = &self.key else {
+            return Some(last);
+        };
+        let cipher = Aes128CbcDec::new(key.as_slice().into(), self.iv.as_slice().into());
+        let length = cipher.decrypt_padded_mut::<Pkcs7>(&mut last).ok()?.len();
+        last.truncate(length);
+        Some(last)
+    }
+}
+
+#[cfg(test)]
+mod decrypt_tests {
+    use super::*;
+    use aes::cipher::BlockEncryptMut;
+
+    #[test]
+    fn chunked_like_whole() {
+        let key = Arc::new(vec![7_u8; 16]);
+        let iv = vec![3_u8; 16];
+        let plain: Vec<u8> = (0..1000).map(|x| x as u8).collect();
+        let cipher =
+            cbc::Encryptor::<aes::Aes128>::new(key.as_slice().into(), iv.as_slice().into());
+        let mut encrypted = plain.clone();
+        encrypted.resize(plain.len() + BLOCK_SIZE, 0);
+        let length = cipher
+            .encrypt_padded_mut::<Pkcs7>(&mut encrypted, plain.len())
+            .unwrap()
+            .len();
+        encrypted.truncate(length);
+
+        let mut decryptor = SegmentDecryptor::new(Some(SegmentKey {
+            key: key.clone(),
+            iv,
+        }));
+        let mut decrypted = Vec::new();
+        for chunk in encrypted.chunks(100) {
+            decrypted.extend(decryptor.update(chunk));
+        }
+        assert!(decryptor.pending().len() <= BLOCK_SIZE);
+
+        // an interrupted transfer continues from the saved state
+        let mut decryptor = SegmentDecryptor::resume(
+            Some(key),
+            decryptor.iv().to_vec(),
+            decryptor.pending().to_vec(),
+        );
+        decrypted.extend(decryptor.update(&[]));
+        decrypted.extend(decryptor.finish().unwrap());
+        assert_eq!(decrypted, plain);
+    }
+}
@@ -0,0 +1,54 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::utils::write_atomically;
+
+const MANIFEST_NAME: &str = "manifest.json";
+const FLUSH_EVERY: usize = 10;
+
+/// parts of a video that were completely written to disk, a part file that is
+/// not listed here is treated as partial and downloaded again
+#[derive(Serialize, Deserialize, Default)]
+pub struct Manifest {
+    parts: BTreeMap<String, u64>,
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    unsaved: usize,
+}
+
+impl Manifest {
+    pub fn load(directory: &Path) -> Self {
+        let path = directory.join(MANIFEST_NAME);
+        let mut manifest: Self = fs::read(&path)
+            .ok()
+            .and_then(|content| serde_json::from_slice(&content).ok())
+            .unwrap_or_default();
+        manifest.path = path;
+        manifest
+    }
+
+    pub fn size_of(&self, part_name: &str) -> Option<u64> {
+        self.parts.get(part_name).copied()
+    }
+
+    pub fn record(&mut self, part_name: String, size: u64) -> std::io::Result<()> {
+        self.parts.insert(part_name, size);
+        self.unsaved += 1;
+        if self.unsaved >= FLUSH_EVERY {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        if self.unsaved == 0 {
+            return Ok(());
+        }
+        write_atomically(&self.path, &serde_json::to_vec(self)?)?;
+        self.unsaved = 0;
+        Ok(())
+    }
+}
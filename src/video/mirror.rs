@@ -0,0 +1,159 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use url::Url;
+
+/// `from => to` host rewrites from `--mirror`, in the order they were given
+static RULES: OnceLock<Vec<(String, String)>> = OnceLock::new();
+
+pub fn set_mirrors(rules: Vec<(String, String)>) {
+    let _ = RULES.set(rules);
+}
+
+/// `cdn1.example=>cdn2.example`
+pub fn parse_mirror(input: &str) -> Result<(String, String), String> {
+    let (from, to) = input
+        .split_once("=>")
+        .ok_or(format!("'{}' is not in from=>to form", input))?;
+    let (from, to) = (from.trim().to_lowercase(), to.trim().to_lowercase());
+    if from.is_empty() || to.is_empty() {
+        return Err(format!("'{}' is missing a host", input));
+    }
+    Ok((from, to))
+}
+
+#[derive(Default)]
+struct HostHealth {
+    failures: u32,
+    bytes: u64,
+    seconds: f64,
+}
+
+impl HostHealth {
+    fn speed(&self) -> u64 {
+        if self.seconds <= 0.0 {
+            return 0;
+        }
+        (self.bytes as f64 / self.seconds) as u64
+    }
+}
+
+/// health of the segment hosts of a single video, a host that keeps failing
+/// drops behind its mirrors and the fastest one is preferred afterwards
+#[derive(Default)]
+pub struct Mirrors {
+    hosts: Mutex<HashMap<String, HostHealth>>,
+}
+
+impl Mirrors {
+    /// `link` and its mirrors, the healthiest and fastest host first
+    pub fn candidates(&self, link: &str) -> Vec<String> {
+        let rules = RULES.get().map(Vec::as_slice).unwrap_or_default();
+        self.rank(link, rules)
+    }
+
+    fn rank(&self, link: &str, rules: &[(String, String)]) -> Vec<String> {
+        let Ok(url) = Url::parse(link) else {
+            return vec![link.to_string()];
+        };
+        let Some(host) = url.host_str().map(str::to_lowercase) else {
+            return vec![link.to_string()];
+        };
+        let mut hosts = vec![host.clone()];
+        hosts.extend(
+            rules
+                .iter()
+                .filter(|(from, _)| *from == host)
+                .map(|(_, to)| to.clone()),
+        );
+
+        let health = self.hosts.lock().unwrap();
+        // stable, so hosts without a record keep the order of the rules
+        hosts.sort_by_key(|host| {
+            health
+                .get(host)
+                .map_or((0, Reverse(0)), |x| (x.failures, Reverse(x.speed())))
+        });
+        hosts
+            .into_iter()
+            .filter_map(|host| {
+                let mut url = url.clone();
+                url.set_host(Some(&host)).ok()?;
+                Some(url.to_string())
+            })
+            .collect()
+    }
+
+    pub fn succeeded(&self, link: &str, bytes: usize, elapsed: Duration) {
+        self.update(link, |health| {
+            health.failures = health.failures.saturating_sub(1);
+            health.bytes += bytes as u64;
+            health.seconds += elapsed.as_secs_f64();
+        });
+    }
+
+    pub fn failed(&self, link: &str) {
+        self.update(link, |health| health.failures += 1);
+    }
+
+    fn update(&self, link: &str, change: impl FnOnce(&mut HostHealth)) {
+        let Some(host) = Url::parse(link)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_lowercase))
+        else {
+            return;
+        };
+        change(self.hosts.lock().unwrap().entry(host).or_default());
+    }
+}
+
+#[cfg(test)]
+mod mirror_tests {
+    use super::*;
+
+    #[test]
+    fn failover_order() -> Result<(), String> {
+        let rules = vec![
+            parse_mirror("cdn1.example=>cdn2.example")?,
+            parse_mirror("cdn1.example => cdn3.example")?,
+        ];
+        let link = "https://cdn1.example/v/seg-1.ts?t=1";
+        let mirrors = Mirrors::default();
+        let hosts = |mirrors: &Mirrors| -> Vec<String> {
+            mirrors
+                .rank(link, &rules)
+                .iter()
+                .map(|x| Url::parse(x).unwrap().host_str().unwrap().to_string())
+                .collect()
+        };
+        assert_eq!(
+            vec!["cdn1.example", "cdn2.example", "cdn3.example"],
+            hosts(&mirrors)
+        );
+        assert_eq!(
+            "https://cdn2.example/v/seg-1.ts?t=1",
+            mirrors.rank(link, &rules)[1]
+        );
+
+        mirrors.failed(link);
+        assert_eq!(
+            vec!["cdn2.example", "cdn3.example", "cdn1.example"],
+            hosts(&mirrors)
+        );
+
+        // once measured the faster mirror wins
+        let second = Duration::from_secs(1);
+        mirrors.succeeded("https://cdn2.example/a.ts", 1000, second);
+        mirrors.succeeded("https://cdn3.example/a.ts", 5000, second);
+        assert_eq!(
+            vec!["cdn3.example", "cdn2.example", "cdn1.example"],
+            hosts(&mirrors)
+        );
+
+        assert!(parse_mirror("cdn1.example").is_err());
+        assert!(parse_mirror("=>cdn2.example").is_err());
+
+        Ok(())
+    }
+}
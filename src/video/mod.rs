@@ -1,31 +1,84 @@
-use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
 use aes_gcm::{
     aead::{Aead, KeyInit},
     Aes256Gcm, Key,
 };
 use base64::{engine::general_purpose::STANDARD, Engine as _};
-use kdam::{tqdm, Bar, BarExt};
+use futures::stream::{self, StreamExt as _};
 use pbkdf2::pbkdf2_hmac;
 use regex::Regex;
-use reqwest::{
-    header::{self, HeaderMap},
-    Client,
-};
+use reqwest::{header, Client, Method, RequestBuilder, StatusCode};
 use serde::{Deserialize, Serialize};
 use sha2::Sha256;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::{collections::LinkedList, fs};
+use std::time::{Duration, Instant};
 use std::{
     io::Write,
     path::{Path, PathBuf},
 };
 use tokio::sync::Mutex;
-use tokio::sync::{OwnedSemaphorePermit, RwLock, Semaphore};
+use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn, Instrument as _, Span};
 
+mod concurrency;
 mod convert;
+mod decrypt;
+mod manifest;
+mod mirror;
+mod playlist;
+mod progress;
+mod quality;
+mod rendition;
+mod section;
+mod segment;
+pub use concurrency::{parse_concurrency, set_concurrency, ConcurrencyMode};
+use concurrency::{Concurrency, Outcome, Slot};
 use convert::convert_video_from_mpeg_to_mp4;
+use decrypt::SegmentKey;
+use manifest::Manifest;
+use mirror::Mirrors;
+pub use mirror::{parse_mirror, set_mirrors};
+use playlist::{ByteRange, Encryption, MediaSegment, Rendition, RenditionKind};
+pub use progress::Progress;
+pub use quality::QualityPolicy;
+pub use rendition::{parse_languages, set_renditions, Languages, RenditionChoice};
+pub use section::{parse_section, set_section, Section};
+use segment::PartialSegment;
+
+use crate::display::VideoBar;
+use crate::events::{self, Event};
+use crate::http::{self, Identity};
+use crate::quota;
+use crate::timer::{Schedule, TimedDownload as _};
+use crate::utils::write_atomically;
+
+const PART_ATTEMPTS: usize = 3;
+
+/// `--audio-only`, saves the audio of videos as m4a files
+static AUDIO_ONLY: AtomicBool = AtomicBool::new(false);
+
+pub fn set_audio_only(is_audio_only: bool) {
+    AUDIO_ONLY.store(is_audio_only, Ordering::Relaxed);
+}
+
+fn host_of(link: &str) -> String {
+    url::Url::parse(link)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default()
+}
 
-use crate::timer::{TimeRange, TimedDownload as _};
+/// replaces the characters windows does not allow in file names
+fn safe_name(text: &str) -> String {
+    let mut safe_name = text.to_string();
+    for char in r#"\/:*?"<>|"#.chars() {
+        safe_name = safe_name.replace(char, "-");
+    }
+    safe_name
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct VideoQuality {
@@ -44,46 +97,224 @@ pub struct VideoData {
 struct VideoInner {
     video_id: String,
     video_host: String,
-    desired_quality: Option<String>,
+    desired_quality: Option<QualityPolicy>,
     quality_index: usize,
-    time_range: Option<TimeRange>,
+    time_range: Option<Schedule>,
     client: Client,
+    /// differs from `client` only when the proxy is limited to one of them
+    segment_client: Client,
+    identity: Identity,
+}
+
+struct Part {
+    index: usize,
+    /// file name inside the video directory
+    name: String,
+    /// subtitle segments are WebVTT text
+    is_mpeg_ts: bool,
+    link: String,
+    /// encrypted size from the playlist or a HEAD request
+    size: Option<usize>,
+    duration: f64,
+    byte_range: Option<ByteRange>,
+    /// `None` for unencrypted segments
+    key: Option<SegmentKey>,
+}
+
+/// media playlist downloaded into the video directory, the video itself or
+/// one of the renditions picked for it
+struct Track {
+    number: usize,
+    rendition: Option<Rendition>,
+    segments: Vec<MediaSegment>,
+    /// position of the first segment in the playlist, segments before a
+    /// `--section` are left out
+    first: usize,
+    /// media time the first segment starts at
+    start: f64,
+}
+
+impl Track {
+    fn new(number: usize, rendition: Option<Rendition>, segments: Vec<MediaSegment>) -> Self {
+        Self {
+            number,
+            rendition,
+            segments,
+            first: 0,
+            start: 0.0,
+        }
+    }
+
+    fn keep_section(&mut self, section: Section) {
+        let (range, start) = section::overlapping(&self.segments, section);
+        self.first = range.start;
+        self.start = start;
+        self.segments = self.segments.drain(range).collect();
+    }
+
+    fn part_name(&self, index: usize, quality_index: usize) -> String {
+        // named after their place in the playlist, parts of another section stay apart
+        let index = self.first + index;
+        match self.rendition.as_ref().map(|x| x.kind) {
+            None => format!("Vpart-{:010}-{:02}.ts", index, quality_index),
+            Some(RenditionKind::Audio) => {
+                format!(
+                    "Apart-{:02}-{:010}-{:02}.ts",
+                    self.number, index, quality_index
+                )
+            }
+            Some(RenditionKind::Subtitles) => {
+                format!(
+                    "Spart-{:02}-{:010}-{:02}.vtt",
+                    self.number, index, quality_index
+                )
+            }
+        }
+    }
+
+    fn is_mpeg_ts(&self) -> bool {
+        !matches!(&self.rendition, Some(x) if x.kind == RenditionKind::Subtitles)
+    }
 }
 
 #[derive(Clone)]
 pub struct Video {
     inner: Arc<RwLock<VideoInner>>,
+    progress: Arc<Progress>,
+}
+
+impl VideoInner {
+    /// clients are shared by every video of a host, so the referer is set on
+    /// each request instead of the client, unless one was given
+    fn referer(&self) -> String {
+        format!("https://{}/{}/iframe", &self.video_host, &self.video_id)
+    }
+
+    fn get(&self, url: &str) -> RequestBuilder {
+        self.identity.apply(self.client.get(url), self.referer())
+    }
+
+    fn segment_request(&self, method: Method, url: &str) -> RequestBuilder {
+        let request = self.segment_client.request(method, url);
+        self.identity.apply(request, self.referer())
+    }
+
+    /// media playlists are usually encrypted like the master playlist, some
+    /// rendition playlists are served as they are
+    async fn fetch_media_playlist(
+        &self,
+        link: &str,
+        msgn: &str,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let res = self.get(link).send().await?;
+        if res.status() != 200 {
+            return Err(format!("Playlist returned {} status", res.status()).into());
+        }
+        let playlist_text = res.text().await?;
+        if playlist_text.starts_with("#EXTM3U") {
+            return Ok(playlist_text);
+        }
+        Video::decrypt_m3u8(msgn, &playlist_text)
+    }
+
+    /// every key is fetched once, a playlist may rotate them between segments
+    async fn fetch_keys(
+        &self,
+        tracks: &[Track],
+    ) -> Result<HashMap<String, Arc<Vec<u8>>>, Box<dyn std::error::Error>> {
+        let mut keys = HashMap::new();
+        for segment in tracks.iter().flat_map(|x| &x.segments) {
+            let Encryption::Aes128 { uri, .. } = &segment.encryption else {
+                continue;
+            };
+            if keys.contains_key(uri) {
+                continue;
+            }
+            let res = self.get(uri).send().await?;
+            debug!("Key uri reponse code: '{}'", res.status());
+            if res.status() != 200 {
+                return Err("Key uri returned none 200 status".into());
+            }
+            let key = res.bytes().await?.to_vec();
+            if key.len() != 16 {
+                return Err(format!("Stream key should be 16 bytes, got {}", key.len()).into());
+            }
+            keys.insert(uri.clone(), Arc::new(key));
+        }
+        if keys.is_empty() {
+            debug!("Stream is not encrypted");
+        }
+        Ok(keys)
+    }
+
+    async fn fetch_embed_data(&self) -> Result<VideoData, Box<dyn std::error::Error>> {
+        let embed_url = format!("https://{}/{}/embed", &self.video_host, &self.video_id);
+
+        let embed_res = self.get(&embed_url).send().await?;
+        if embed_res.status() != 200 {
+            error!("cannot get embed.js file");
+            return Err("".into());
+        }
+
+        let embed_body = embed_res.text().await?;
+
+        let regex = Regex::new(r"'.*?'").unwrap();
+        let mut data_wraps = regex.find_iter(&embed_body);
+
+        let embed_video_data: VideoData;
+
+        match data_wraps.nth(24) {
+            Some(matched) => {
+                let base_64_json = matched.as_str();
+                debug!("embed data {}", base_64_json);
+                let base_64_json_str = &base_64_json[1..base_64_json.len() - 1];
+
+                let decoded_json_string = STANDARD.decode(base_64_json_str)?;
+                embed_video_data = serde_json::from_slice(&decoded_json_string)?;
+            }
+            None => {
+                return Err("Cannot extract embed data".into());
+            }
+        }
+        Ok(embed_video_data)
+    }
 }
 
 impl Video {
     pub async fn print_extracted(&self) {
         let inner = self.inner.read().await;
-        println!("[Video host] {}", &inner.video_host);
-        println!("[Video id] {}", &inner.video_id);
+        info!("video host {}", &inner.video_host);
+        info!("video id {}", &inner.video_id);
     }
 
-    pub async fn set_time_range(&mut self, time_range: TimeRange) {
+    pub async fn set_time_range(&mut self, time_range: Schedule) {
         self.inner.write().await.time_range = Some(time_range);
     }
 
-    pub fn new(video_id: String, video_host: String, desired_quality: Option<String>) -> Self {
-        let mut headers = HeaderMap::new();
-        headers.insert(
-            header::REFERER,
-            format!("https://{}/{}/iframe", &video_host, &video_id)
-                .parse()
-                .unwrap(),
-        );
+    /// advertised size of the quality a batch download picks, fetched before
+    /// the download so the timer can tell whether the video fits its window
+    pub async fn advertised_size(&self) -> Result<usize, Box<dyn std::error::Error>> {
+        let inner = self.inner.read().await;
+        let embed_video_data = inner.fetch_embed_data().await?;
+        let index = match &inner.desired_quality {
+            Some(policy) => policy.select(&embed_video_data.download)?,
+            None => 0,
+        };
+        let quality = embed_video_data
+            .download
+            .get(index)
+            .ok_or("Video has no downloadable quality")?;
+        Ok(quality.size.parse()?)
+    }
+
+    pub fn progress(&self) -> Arc<Progress> {
+        self.progress.clone()
+    }
 
-        let client = reqwest::ClientBuilder::new()
-            .user_agent(
-                "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:122.0) Gecko/20100101 Firefox/122.0",
-            )
-            .default_headers(headers)
-            .build()
-            .unwrap();
+    pub fn new(video_id: String, video_host: String, desired_quality: Option<QualityPolicy>, identity: Identity) -> Result<Self, reqwest::Error> {
+        let (client, segment_client) = http::clients(&video_host, &identity)?;
 
-        Self {
+        Ok(Self {
             inner: Arc::new(RwLock::new(VideoInner {
                 video_id,
                 video_host,
@@ -91,8 +322,11 @@ impl Video {
                 desired_quality,
                 time_range: None,
                 client,
+                segment_client,
+                identity,
             })),
-        }
+            progress: Arc::new(Progress::default()),
+        })
     }
 
     fn decrypt_m3u8(msgn: &str, m3u8_text: &str) -> Result<String, Box<dyn std::error::Error>> {
@@ -120,101 +354,117 @@ impl Video {
         }
     }
 
-    pub async fn download(&self, is_in_batch: bool) -> Result<(), Box<dyn std::error::Error>> {
-        let mut self_data = self.inner.write().await;
-
-        let download_timer = self_data.time_range.clone();
-        self_data.time_range.should_coutinue();
-
-        println!("[Progress] fetching embed files");
-
-        let embed_url = format!(
-            "https://{}/{}/embed",
-            &self_data.video_host, &self_data.video_id
-        );
-
-        let embed_res = self_data.client.get(&embed_url).send().await?;
-        if embed_res.status() != 200 {
-            println!("[Error] cannot get embed.js file");
-            return Err("".into());
+    pub async fn download(
+        &self,
+        is_in_batch: bool,
+        cancel: CancellationToken,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (video_id, span) = {
+            let inner = self.inner.read().await;
+            let span = tracing::info_span!(
+                "video",
+                host = %inner.video_host,
+                video_id = %inner.video_id,
+                quality = tracing::field::Empty,
+            );
+            (inner.video_id.clone(), span)
+        };
+        let result = self
+            .download_video(is_in_batch, cancel)
+            .instrument(span)
+            .await;
+        if let Err(err) = &result {
+            events::emit(Event::JobFailed {
+                video_id,
+                error: err.to_string(),
+            });
         }
+        result
+    }
 
-        let embed_body = embed_res.text().await?;
+    async fn download_video(
+        &self,
+        is_in_batch: bool,
+        cancel: CancellationToken,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut self_data = self.inner.write().await;
 
-        let regex = Regex::new(r"'.*?'").unwrap();
-        let mut data_wraps = regex.find_iter(&embed_body);
+        events::emit(Event::JobStarted {
+            video_id: self_data.video_id.clone(),
+            host: self_data.video_host.clone(),
+        });
 
-        let embed_video_data: VideoData;
+        let download_timer = self_data.time_range.clone();
+        tokio::select! {
+            _ = cancel.cancelled() => return Err("Download cancelled".into()),
+            _ = async {
+                download_timer.wait_until_in_range().await;
+                quota::wait_until_available().await;
+            } => (),
+        }
 
-        match data_wraps.nth(24) {
-            Some(matched) => {
-                let base_64_json = matched.as_str();
-                println!("[Embed data] {}", base_64_json);
-                let base_64_json_str = &base_64_json[1..base_64_json.len() - 1];
+        info!("fetching embed files");
 
-                let decoded_json_string = STANDARD.decode(base_64_json_str)?;
-                embed_video_data = serde_json::from_slice(&decoded_json_string)?;
-            }
-            None => {
-                return Err("Cannot extract embed data".into());
-            }
-        }
+        let embed_video_data = self_data.fetch_embed_data().await?;
 
-        let mut safe_title = embed_video_data.title;
-        for char in r#"\/:*?"<>|"#.chars() {
-            safe_title = safe_title.replace(char, "-");
-        }
+        let safe_title = safe_name(&embed_video_data.title);
 
-        match fs::metadata(format!("{}.mp4", &safe_title)) {
+        let is_audio_only = AUDIO_ONLY.load(Ordering::Relaxed);
+        let extension = if is_audio_only { "m4a" } else { "mp4" };
+        // sections of the same video are saved side by side
+        let output_name = match section::section() {
+            Some(section) => format!("{} [{}]", &safe_title, section.file_suffix()),
+            None => safe_title.clone(),
+        };
+        match fs::metadata(format!("{}.{}", &output_name, extension)) {
             Ok(_) => {
                 return Err("Video already downloaded".into());
             }
             Err(_) => (),
         }
 
-        println!("[Progress] Fetching playlists");
+        info!("Fetching playlists");
 
         let playlist_url = format!(
             "https://{}/{}.m3u8",
             &self_data.video_host, &embed_video_data.playlist
         );
 
-        let playlist_res = self_data.client.get(playlist_url).send().await?;
+        let playlist_res = self_data.get(&playlist_url).send().await?;
         if playlist_res.status() != 200 {
-            println!("[Error] Cannot get playlist file");
+            error!("Cannot get playlist file");
             return Err("".into());
         }
 
         let encrypted_playlist_text = playlist_res.text().await?;
 
         let playlist_text = Self::decrypt_m3u8(&embed_video_data.msgn, &encrypted_playlist_text)?;
-        if !is_in_batch && self_data.desired_quality.is_none() {
-            println!("[Prompt] Select desired quality: ");
+        if !is_in_batch && self_data.desired_quality.is_none() && !is_audio_only {
+            report!("[Prompt] Select desired quality: ");
             for (index, video_quality) in embed_video_data.download.iter().enumerate() {
-                println!("[Choice] {} -> {}", video_quality.name, index);
+                report!("[Choice] {} -> {}", video_quality.name, index);
             }
         }
 
+        let (variants, renditions) = playlist::parse_master(&playlist_text);
         let mut index_string = String::from("0");
         let mut valid_selection = false;
-        let mut selected_playlist_link = "";
+        let mut selected_variant = None;
         let mut q_index = 0;
-        if let Some(desired_quality) = &self_data.desired_quality {
-            let desired_quality = desired_quality.to_owned() + "p";
-            let found_index = embed_video_data
-                .download
-                .iter()
-                .position(|x| x.name == desired_quality)
-                .ok_or(format!(
-                    "Specified quality {} is unavalable in video",
-                    &desired_quality
-                ))?;
-            let target_line = (found_index + 1) * 2;
-            match playlist_text.split('\n').nth(target_line) {
-                Some(link) => {
+        if is_audio_only {
+            let lowest = quality::smallest_variant(&variants, &embed_video_data.download);
+            if let Some(index) = lowest {
+                valid_selection = true;
+                q_index = index;
+                selected_variant = variants.get(index);
+            }
+        } else if let Some(desired_quality) = &self_data.desired_quality {
+            let found_index = desired_quality.select(&embed_video_data.download)?;
+            match variants.get(found_index) {
+                Some(variant) => {
                     valid_selection = true;
                     q_index = found_index;
-                    selected_playlist_link = link;
+                    selected_variant = Some(variant);
                 }
                 None => {
                     // it's impossible because index is already found in embed data
@@ -229,21 +479,18 @@ impl Video {
                 index_string = index_string.trim().to_string();
             }
             match index_string.parse::<usize>() {
-                Ok(index) => {
-                    let target_line = (index + 1) * 2;
-                    match playlist_text.split('\n').nth(target_line) {
-                        Some(link) => {
-                            valid_selection = true;
-                            q_index = index;
-                            selected_playlist_link = link;
-                        }
-                        None => {
-                            println!("[Error] Index out of range try again:");
-                        }
+                Ok(index) => match variants.get(index) {
+                    Some(variant) => {
+                        valid_selection = true;
+                        q_index = index;
+                        selected_variant = Some(variant);
                     }
-                }
+                    None => {
+                        report!("[Error] Index out of range try again:");
+                    }
+                },
                 Err(_) => {
-                    println!("[Error] Cannot parse input to usize try again:");
+                    report!("[Error] Cannot parse input to usize try again:");
                 }
             }
             if is_in_batch && !valid_selection {
@@ -253,186 +500,581 @@ impl Video {
 
         self_data.quality_index = q_index;
 
-        let playlist_m3u8_res = self_data.client.get(selected_playlist_link).send().await?;
-        if playlist_m3u8_res.status() != 200 {
-            println!("[Error] Cannot get playlist parts");
-            return Err("".into());
-        }
-
-        let encrypted_playlist_text = playlist_m3u8_res.text().await?;
-        let playlist_text = Self::decrypt_m3u8(&embed_video_data.msgn, &encrypted_playlist_text)?;
-        let lines = playlist_text.split('\n');
-
-        let mut part_links = LinkedList::new();
-        let mut cipher_iv = Vec::new();
-        let mut cipher_key = Vec::new();
-
-        for line in lines {
-            if line.starts_with("#EXT-X-KEY") {
-                match line.split("IV=").last() {
-                    Some(iv_hex) => {
-                        cipher_iv = hex::decode(&iv_hex[2..])?;
-                    }
-                    None => {
-                        return Err("Cannot find stream iv".into());
-                    }
-                }
+        let selected_quality = &embed_video_data.download[q_index];
+        Span::current().record("quality", selected_quality.name.as_str());
+        events::emit(Event::QualitySelected {
+            video_id: self_data.video_id.clone(),
+            title: safe_title.clone(),
+            quality: selected_quality.name.clone(),
+            size: selected_quality.size.parse().unwrap_or(0),
+        });
 
-                match line.split('"').nth(1) {
-                    Some(link) => {
-                        let res = self_data.client.get(link).send().await?;
-                        println!("[Progress] Key uri reponse code: '{}'", res.status());
-                        if res.status() != 200 {
-                            return Err("Key uri returned none 200 status".into());
-                        }
-                        cipher_key = res.bytes().await?.to_vec();
-                    }
-                    None => {
-                        return Err("Cannot find stream key uri".into());
-                    }
+        let variant = selected_variant.ok_or("No variant selected")?;
+        let msgn = &embed_video_data.msgn;
+        let rendition_choice = rendition::choice();
+        let mut selected_renditions = rendition::select(&rendition_choice, variant, &renditions);
+        if is_audio_only {
+            // a separate audio track is all that is needed, the video is only
+            // downloaded for its audio when there is none
+            selected_renditions.retain(|x| x.kind == RenditionKind::Audio);
+            selected_renditions.truncate(1);
+        }
+        // the first track is the one converted, the others are muxed into it
+        let mut tracks = Vec::new();
+        if !is_audio_only || selected_renditions.is_empty() {
+            let playlist_text = match self_data.fetch_media_playlist(&variant.uri, msgn).await {
+                Ok(playlist_text) => playlist_text,
+                Err(err) => {
+                    error!("Cannot get playlist parts");
+                    return Err(err);
                 }
+            };
+            let segments = playlist::parse_segments(&playlist_text)?;
+            tracks.push(Track::new(0, None, segments));
+        }
+        for rendition in selected_renditions {
+            let uri = rendition.uri.as_deref().unwrap_or_default();
+            let playlist_text = self_data.fetch_media_playlist(uri, msgn).await?;
+            info!("Adding {:?} track '{}'", rendition.kind, rendition.label());
+            let segments = playlist::parse_segments(&playlist_text)?;
+            tracks.push(Track::new(tracks.len(), Some(rendition), segments));
+        }
+        let section = section::section();
+        if let Some(section) = section {
+            for track in &mut tracks {
+                track.keep_section(section);
             }
-            if line.starts_with("https://") {
-                part_links.push_back(line.to_string());
+            if tracks[0].segments.is_empty() {
+                return Err("The section is past the end of the video".into());
             }
+            info!(
+                "Downloading {} parts of the section",
+                tracks[0].segments.len()
+            );
         }
+        let keys = self_data.fetch_keys(&tracks).await?;
 
-        let download_semaphore = Arc::new(Semaphore::new(10));
+        let concurrency = Arc::new(Concurrency::new());
         let mut download_handles = Vec::new();
+        // a part that cannot be downloaded stops the other parts of this video only
+        let parts_cancel = cancel.child_token();
 
         let _ = fs::create_dir(&self_data.video_id);
-        let arc_cipher_key = Arc::new(cipher_key);
-        let arc_cipher_iv = Arc::new(cipher_iv);
-
-        let total_size = embed_video_data.download[self_data.quality_index]
-            .size
-            .parse::<usize>()?;
-
-        let pb = tqdm!(
-            total = total_size,
-            unit_scale = true,
-            unit_divisor = 1024,
-            unit = "B"
-        );
 
+        let segment_sizes = tokio::select! {
+            _ = cancel.cancelled() => return Err("Download cancelled".into()),
+            sizes = async {
+                let mut sizes = Vec::new();
+                for track in &tracks {
+                    sizes.push(Self::segment_sizes(&self_data, &track.segments).await);
+                }
+                sizes
+            } => sizes,
+        };
+        // the advertised size is only a fallback, it rarely matches what is transferred
+        let total_size = if segment_sizes.iter().flatten().all(Option::is_some) {
+            segment_sizes.iter().flatten().flatten().sum()
+        } else {
+            debug!("Some segments have no known size, using advertised size");
+            embed_video_data.download[self_data.quality_index]
+                .size
+                .parse::<usize>()?
+        };
+        // the other tracks cover the same media time
+        let total_duration = tracks[0].segments.iter().map(|x| x.duration).sum();
+
+        let mut pb = VideoBar::new(&safe_title, total_size, total_duration);
+        if concurrency.is_adaptive() {
+            pb.set_concurrency(concurrency.level());
+        }
+
+        self.progress.start(total_size, total_duration);
         let pb = Arc::new(Mutex::new(pb));
         let directory_path = PathBuf::from(&self_data.video_id);
+        let manifest = Arc::new(Mutex::new(Manifest::load(&directory_path)));
+        let mirrors = Arc::new(Mirrors::default());
         drop(self_data);
 
-        let mut index_counter = 0;
-        while let Some(link) = part_links.pop_front() {
-            download_timer.should_coutinue();
-            let index = index_counter;
-            index_counter += 1;
-            let semaphore = download_semaphore.clone();
-            let permit = semaphore.acquire_owned().await?;
-            let iv = arc_cipher_iv.clone();
-            let key = arc_cipher_key.clone();
-            let pb = pb.clone();
-            let fut = self.clone().download_part(index, link, permit, iv, key, pb);
-            let handle = tokio::spawn(fut);
+        let quality_index = self.inner.read().await.quality_index;
+        let mut parts = Vec::new();
+        for (track, sizes) in tracks.iter().zip(segment_sizes) {
+            for (index, (segment, size)) in track.segments.iter().zip(sizes).enumerate() {
+                parts.push((track, index, segment, size));
+            }
+        }
+        for (track, index, segment, size) in parts {
+            if parts_cancel.is_cancelled() {
+                break;
+            }
+            let slot = tokio::select! {
+                _ = parts_cancel.cancelled() => break,
+                slot = async {
+                    download_timer.wait_until_in_range().await;
+                    quota::wait_until_available().await;
+                    self.progress.wait_while_paused().await;
+                    concurrency.acquire().await
+                } => slot?,
+            };
+            let key = match &segment.encryption {
+                Encryption::None => None,
+                Encryption::Aes128 { uri, .. } => Some(SegmentKey {
+                    key: keys[uri].clone(),
+                    iv: segment.iv().unwrap_or_default(),
+                }),
+            };
+            let part = Part {
+                index,
+                name: track.part_name(index, quality_index),
+                is_mpeg_ts: track.is_mpeg_ts(),
+                link: segment.link.clone(),
+                size,
+                duration: if track.number == 0 {
+                    segment.duration
+                } else {
+                    0.0
+                },
+                byte_range: segment.byte_range,
+                key,
+            };
+            let fut = self.clone().download_part(
+                part,
+                slot,
+                pb.clone(),
+                manifest.clone(),
+                mirrors.clone(),
+                parts_cancel.clone(),
+            );
+            let parts_cancel = parts_cancel.clone();
+            let handle = tokio::spawn(
+                async move {
+                    let result = fut.await;
+                    if result.is_err() {
+                        parts_cancel.cancel();
+                    }
+                    result
+                }
+                .in_current_span(),
+            );
             download_handles.push(handle);
         }
 
+        let mut part_error = None;
         for handle in download_handles {
-            handle.await?;
+            if let Err(err) = handle.await? {
+                part_error.get_or_insert(err);
+            }
         }
 
-        let self_data = self.inner.read().await;
+        manifest.lock().await.flush()?;
+        if let Some(err) = part_error {
+            return Err(err);
+        }
+        if cancel.is_cancelled() {
+            return Err("Download cancelled, finished parts are kept for the next run".into());
+        }
 
-        download_timer.should_coutinue();
+        tokio::select! {
+            _ = cancel.cancelled() => return Err("Download cancelled".into()),
+            _ = download_timer.wait_until_in_range() => (),
+        }
 
-        println!("[Progress] Created mpeg video");
+        let self_data = self.inner.read().await;
+
+        info!("Created mpeg video");
 
         let mut outfile = fs::File::create(directory_path.join("placeholder.mpeg"))?;
-        for index in 0..index_counter {
-            let name = Self::part_name(index, self_data.quality_index);
+        for index in 0..tracks[0].segments.len() {
+            let name = tracks[0].part_name(index, self_data.quality_index);
             let file_content = fs::read(directory_path.join(name))?;
             outfile.write_all(&file_content)?;
         }
+        drop(outfile);
 
-        let input_file = directory_path.join("placeholder.mpeg\0");
-        let input_file = input_file
-            .to_str()
-            .ok_or("Cannot convert PathBuf to &str")?;
-        let output_file = format!("{}.mp4\0", &safe_title);
+        // media time the output starts at, the section is cut at a keyframe
+        let mut output_start = 0.0;
+        let mut main_path = directory_path.join("placeholder.mpeg");
+        if let Some(section) = section {
+            let track = &tracks[0];
+            let section_path = directory_path.join("section.mpeg");
+            let (start, end) = (section.start - track.start, section.end - track.start);
+            let cut = convert::trim(&main_path, &section_path, start, end)?;
+            output_start = track.start + cut;
+            main_path = section_path;
+        }
 
-        unsafe {
-            convert_video_from_mpeg_to_mp4(
-                input_file.as_ptr() as *const libc::c_char,
-                output_file.as_ptr() as *const libc::c_char,
-            );
+        // subtitle segments are timed against the mpeg-ts clock of the video
+        let first_part = tracks[0].part_name(0, self_data.quality_index);
+        let mpegts_start = fs::read(directory_path.join(first_part))
+            .ok()
+            .and_then(|bytes| segment::earliest_pts(&bytes))
+            .map(|pts| rendition::mpegts_start(pts, tracks[0].start));
+
+        let mut extra_tracks = Vec::new();
+        for track in &tracks[1..] {
+            let Some(rendition) = &track.rendition else {
+                continue;
+            };
+            let names =
+                (0..track.segments.len()).map(|x| track.part_name(x, self_data.quality_index));
+            match rendition.kind {
+                RenditionKind::Audio => {
+                    let path = directory_path.join(format!("audio-{:02}.ts", track.number));
+                    let mut outfile = fs::File::create(&path)?;
+                    for name in names {
+                        outfile.write_all(&fs::read(directory_path.join(name))?)?;
+                    }
+                    drop(outfile);
+                    let path = match section {
+                        Some(section) => {
+                            let section_path = path.with_extension("section.ts");
+                            let (start, end) =
+                                (output_start - track.start, section.end - track.start);
+                            convert::trim(&path, &section_path, start, end)?;
+                            section_path
+                        }
+                        None => path,
+                    };
+                    extra_tracks.push((path, rendition.label().to_string()));
+                }
+                RenditionKind::Subtitles => {
+                    let mut segments = Vec::new();
+                    for name in names {
+                        let bytes = fs::read(directory_path.join(name))?;
+                        segments.push(String::from_utf8_lossy(&bytes).into_owned());
+                    }
+                    let mut subtitles = rendition::merge_webvtt(&segments, mpegts_start);
+                    if let Some(section) = section {
+                        let length = section.end - output_start;
+                        subtitles = rendition::shift_webvtt(&subtitles, output_start, length);
+                    }
+                    if rendition_choice.is_sidecar {
+                        let path = format!("{}.{}.vtt", &output_name, safe_name(rendition.label()));
+                        fs::write(&path, subtitles)?;
+                        info!("Subtitles saved to '{}'", path);
+                    } else {
+                        let path =
+                            directory_path.join(format!("subtitles-{:02}.vtt", track.number));
+                        fs::write(&path, subtitles)?;
+                        extra_tracks.push((path, rendition.label().to_string()));
+                    }
+                }
+            }
+        }
+
+        let output_file = format!("{}.{}\0", &output_name, extension);
+        if is_audio_only {
+            convert::extract_audio_to_m4a(
+                &main_path,
+                output_file.trim_end_matches('\0'),
+                &embed_video_data.title,
+            )?;
+        } else if extra_tracks.is_empty() {
+            let input_file = main_path.to_str().ok_or("Cannot convert PathBuf to &str")?;
+            let input_file = format!("{}\0", input_file);
+
+            unsafe {
+                convert_video_from_mpeg_to_mp4(
+                    input_file.as_ptr() as *const libc::c_char,
+                    output_file.as_ptr() as *const libc::c_char,
+                );
+            }
+        } else {
+            convert::mux_video_with_tracks(
+                &main_path,
+                &extra_tracks,
+                output_file.trim_end_matches('\0'),
+            )?;
         }
 
-        println!("[Progress] Mp4 video created");
+        if is_audio_only {
+            info!("M4a audio created");
+        } else {
+            info!("Mp4 video created");
+        }
+        events::emit(Event::JobFinished {
+            video_id: self_data.video_id.clone(),
+            output: output_file.trim_end_matches('\0').to_string(),
+        });
 
         let _ = fs::remove_dir_all(directory_path);
 
-        println!("[Progress] Directory deleted");
+        info!("Directory deleted");
 
         Ok(())
     }
 
-    fn part_name(index: usize, quality_index: usize) -> String {
-        format!("Vpart-{:010}-{:02}.ts", index, quality_index)
-    }
-
     async fn download_part(
         self,
-        index: usize,
-        link: String,
-        _permit: OwnedSemaphorePermit,
-        iv: Arc<Vec<u8>>,
-        key: Arc<Vec<u8>>,
-        pb: Arc<Mutex<Bar>>,
-    ) {
+        part: Part,
+        slot: Slot,
+        pb: Arc<Mutex<VideoBar>>,
+        manifest: Arc<Mutex<Manifest>>,
+        mirrors: Arc<Mirrors>,
+        cancel: CancellationToken,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let Part {
+            index,
+            name,
+            is_mpeg_ts,
+            link,
+            size: expected_size,
+            duration,
+            byte_range,
+            key,
+        } = part;
         let self_inner = self.inner.read().await;
 
         let path = Path::new(&self_inner.video_id);
-        let name = Self::part_name(index, self_inner.quality_index);
-        let file_path = path.join(name);
+        let file_path = path.join(&name);
 
-        match fs::metadata(&file_path) {
-            Ok(file) => {
-                let size = file.len();
-                let mut bar = pb.lock().await;
-                bar.update(size as usize).unwrap();
-                return;
+        let recorded_size = manifest.lock().await.size_of(&name);
+        if let Some(size) = recorded_size {
+            if segment::is_complete(&file_path, size, is_mpeg_ts) {
+                let bytes = expected_size.unwrap_or(size as usize);
+                self.advance(&pb, bytes, duration).await;
+                self.progress.resumed(bytes);
+                events::emit(Event::SegmentDone {
+                    video_id: self_inner.video_id.clone(),
+                    index,
+                    bytes,
+                    duration,
+                    resumed: true,
+                });
+                return Ok(());
             }
-            Err(_) => (),
         }
 
-        let res = self_inner.client.get(link).send().await.unwrap();
+        let attempts = PART_ATTEMPTS.max(mirrors.candidates(&link).len());
+        let mut tried = Vec::new();
+        let mut failure = String::new();
+        let mut is_corrupted = false;
+        for attempt in 1..=attempts {
+            // parts that are already transferring finish, the next attempt
+            // waits until the window opens again
+            tokio::select! {
+                _ = cancel.cancelled() => return Ok(()),
+                _ = async {
+                    self_inner.time_range.wait_until_in_range().await;
+                    quota::wait_until_available().await;
+                } => (),
+            }
+            // ranked again every attempt, other parts report on the hosts too
+            let candidates = mirrors.candidates(&link);
+            let source = candidates
+                .iter()
+                .find(|x| !tried.contains(*x))
+                .unwrap_or(&candidates[(attempt - 1) % candidates.len()])
+                .clone();
+            tried.push(source.clone());
+            let retry = |reason: String| {
+                warn!(
+                    "Part {} failed, {} (attempt {}/{})",
+                    index, &reason, attempt, attempts
+                );
+                events::emit(Event::Retry {
+                    video_id: self_inner.video_id.clone(),
+                    index,
+                    attempt,
+                    reason: reason.clone(),
+                });
+                reason
+            };
 
-        // corrupted part
-        if res.status() == 502 || res.status() == 504 {
-            println!("[WARNING] Part {} of video seems to be corrupted you will experience some freezeing", index);
-            let _file = fs::File::create(file_path).unwrap();
-            return;
+            // the body is decrypted into a partial file as it arrives, so a
+            // dropped connection or a cancelled run continues from there
+            let mut partial = match PartialSegment::open(&file_path, key.clone(), is_mpeg_ts) {
+                Ok(partial) => partial,
+                Err(err) => {
+                    let bad_file_path = file_path.to_string_lossy();
+                    return Err(
+                        format!("Cannot write file at path {}\n{}", &bad_file_path, err).into(),
+                    );
+                }
+            };
+            let done = partial.received();
+            // an earlier attempt may have received the whole body already
+            let is_whole = done > 0 && expected_size.is_some_and(|size| done >= size as u64);
+            let started = Instant::now();
+            if !is_whole {
+                let mut request = self_inner.segment_request(Method::GET, &source);
+                if let Some(range) = playlist::range_header(byte_range, done) {
+                    request = request.header(header::RANGE, range);
+                }
+                let fetched = tokio::select! {
+                    _ = cancel.cancelled() => return Ok(()),
+                    fetched = Self::fetch_part(request, byte_range, &mut partial) => fetched,
+                };
+                let (status, latency, received) = match fetched {
+                    Ok(fetched) => fetched,
+                    Err(err) => {
+                        self.record_outcome(&slot, Outcome::Failure, &pb).await;
+                        mirrors.failed(&source);
+                        failure = retry(err.to_string());
+                        continue;
+                    }
+                };
+                // other client errors say nothing about the load on the host
+                if status.is_success() {
+                    let outcome = Outcome::Success {
+                        bytes: received,
+                        latency,
+                    };
+                    self.record_outcome(&slot, outcome, &pb).await;
+                } else if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+                    self.record_outcome(&slot, Outcome::Failure, &pb).await;
+                }
+
+                if !status.is_success() {
+                    mirrors.failed(&source);
+                    // corrupted part, unless a mirror still has it
+                    let is_gateway_error = status == 502 || status == 504;
+                    if is_gateway_error && candidates.iter().all(|x| tried.contains(x)) {
+                        is_corrupted = true;
+                        break;
+                    }
+                    failure = retry(format!("status {} from {}", status, host_of(&source)));
+                    continue;
+                }
+            }
+            // a body that ended early is resumed by the next attempt
+            let received = partial.received();
+            if partial.is_valid() && expected_size.is_some_and(|size| received < size as u64) {
+                mirrors.failed(&source);
+                failure = retry(format!("incomplete body from {}", host_of(&source)));
+                continue;
+            }
+
+            let transferred = expected_size.unwrap_or(received as usize);
+            match partial.finish() {
+                Ok(Some(size)) => {
+                    mirrors.succeeded(&source, transferred, started.elapsed());
+                    manifest.lock().await.record(name, size)?;
+
+                    self.advance(&pb, transferred, duration).await;
+                    events::emit(Event::SegmentDone {
+                        video_id: self_inner.video_id.clone(),
+                        index,
+                        bytes: transferred,
+                        duration,
+                        resumed: false,
+                    });
+                    return Ok(());
+                }
+                Ok(None) => {
+                    mirrors.failed(&source);
+                    failure = retry(format!("invalid mpeg-ts stream from {}", host_of(&source)));
+                }
+                Err(err) => {
+                    let bad_file_path = file_path.to_string_lossy();
+                    return Err(
+                        format!("Cannot write file at path {}\n{}", &bad_file_path, err).into(),
+                    );
+                }
+            }
         }
-        let bytes = res.bytes().await.unwrap();
-        let mut bytes = bytes.to_vec();
-        let cipher =
-            cbc::Decryptor::<aes::Aes128>::new(key.as_slice().into(), iv.as_slice().into());
-
-        let decrypted_bytes = cipher.decrypt_padded_mut::<Pkcs7>(&mut bytes).unwrap();
-
-        let file = fs::File::create(&file_path);
-        let mut file = match file {
-            Ok(x) => x,
-            Err(err) => {
-                let bad_file_path = file_path.to_string_lossy();
-                panic!(
-                    "Cannot Open file at path {}\n{}",
-                    &bad_file_path,
-                    err.to_string()
-                );
+        if !is_corrupted {
+            return Err(format!("Cannot download part {} due {}", index, failure).into());
+        }
+
+        warn!("Part {} of video seems to be corrupted you will experience some freezeing", index);
+        // not recorded in the manifest, the next run tries the part again
+        write_atomically(&file_path, &[])?;
+        PartialSegment::discard(&file_path);
+        self.advance(&pb, expected_size.unwrap_or(0), duration).await;
+        Ok(())
+    }
+
+    /// streams the body into the partial segment, continuing it when the server
+    /// honoured the range and starting over when it sent the whole segment;
+    /// returns the status, the time to the response and the bytes received
+    async fn fetch_part(
+        request: RequestBuilder,
+        byte_range: Option<ByteRange>,
+        partial: &mut PartialSegment,
+    ) -> Result<(StatusCode, Duration, usize), Box<dyn std::error::Error + Send + Sync>> {
+        let started = Instant::now();
+        let mut res = request.send().await?;
+        let latency = started.elapsed();
+        let status = res.status();
+        if !status.is_success() {
+            // the partial is longer than what the server has, so it is stale
+            if status == StatusCode::RANGE_NOT_SATISFIABLE {
+                partial.restart()?;
             }
+            let body = res.bytes().await?;
+            quota::record(body.len());
+            return Ok((status, latency, body.len()));
+        }
+
+        if partial.received() > 0 && status != StatusCode::PARTIAL_CONTENT {
+            partial.restart()?;
+        }
+        // a server that ignores the range of a `#EXT-X-BYTERANGE` segment sends
+        // the whole resource, only the range of it belongs to the part
+        let whole_resource = byte_range.filter(|_| status != StatusCode::PARTIAL_CONTENT);
+        let mut received = 0;
+        while let Some(chunk) = res.chunk().await? {
+            quota::record(chunk.len());
+            let position = received as u64;
+            received += chunk.len();
+            match whole_resource {
+                Some(range) => partial.write(playlist::slice_to_range(&chunk, position, range))?,
+                None => partial.write(&chunk)?,
+            }
+            if !partial.is_valid() {
+                break;
+            }
+            if whole_resource.is_some_and(|range| received as u64 >= range.offset + range.length) {
+                break;
+            }
+        }
+        Ok((status, latency, received))
+    }
+
+    /// feeds the adaptive concurrency and reports when its level changes
+    async fn record_outcome(&self, slot: &Slot, outcome: Outcome, pb: &Mutex<VideoBar>) {
+        let Some(level) = slot.record(outcome) else {
+            return;
         };
+        debug!("Downloading {} parts at the same time", level);
+        pb.lock().await.set_concurrency(level);
+        events::emit(Event::ConcurrencyChanged {
+            video_id: self.inner.read().await.video_id.clone(),
+            level,
+        });
+    }
 
-        file.write_all(decrypted_bytes).unwrap();
+    async fn advance(&self, pb: &Mutex<VideoBar>, bytes: usize, duration: f64) {
+        self.progress.advance(bytes, duration);
+        pb.lock().await.update(bytes, duration);
+    }
 
-        let mut bar = pb.lock().await;
-        bar.update(decrypted_bytes.len()).unwrap();
+    /// encrypted size of every segment, from its byte range or a HEAD request
+    async fn segment_sizes(inner: &VideoInner, segments: &[MediaSegment]) -> Vec<Option<usize>> {
+        // owned futures, borrowing segments here makes the download future lose Send
+        let requests: Vec<_> = segments
+            .iter()
+            .map(|segment| {
+                let request = inner.segment_request(Method::HEAD, &segment.link);
+                let byte_range = segment.byte_range;
+                async move {
+                    if let Some(range) = byte_range {
+                        return Some(range.length as usize);
+                    }
+                    let res = request.send().await.ok()?;
+                    if !res.status().is_success() {
+                        return None;
+                    }
+                    // content_length() of reqwest is always zero for HEAD responses
+                    res.headers()
+                        .get(header::CONTENT_LENGTH)?
+                        .to_str()
+                        .ok()?
+                        .parse()
+                        .ok()
+                }
+            })
+            .collect();
+        stream::iter(requests).buffered(10).collect().await
     }
 }
@@ -0,0 +1,383 @@
+/// sub-range of a resource a segment is stored in, from `#EXT-X-BYTERANGE`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ByteRange {
+    pub length: u64,
+    pub offset: u64,
+}
+
+impl ByteRange {
+    pub fn header_value(&self) -> String {
+        format!("bytes={}-{}", self.offset, self.offset + self.length - 1)
+    }
+}
+
+/// `Range` of what is still missing from a segment after `done` bytes of it
+pub fn range_header(byte_range: Option<ByteRange>, done: u64) -> Option<String> {
+    match byte_range {
+        Some(range) => Some(
+            ByteRange {
+                length: range.length - done,
+                offset: range.offset + done,
+            }
+            .header_value(),
+        ),
+        None if done > 0 => Some(format!("bytes={}-", done)),
+        None => None,
+    }
+}
+
+/// the bytes of a chunk, that starts `position` bytes into the whole resource,
+/// which fall inside the byte range of the segment
+pub fn slice_to_range(chunk: &[u8], position: u64, range: ByteRange) -> &[u8] {
+    let window = |offset: u64| offset.saturating_sub(position).min(chunk.len() as u64) as usize;
+    let start = window(range.offset);
+    let end = window(range.offset + range.length);
+    &chunk[start..end.max(start)]
+}
+
+/// how the following segments are encrypted, from `#EXT-X-KEY`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Encryption {
+    None,
+    Aes128 { uri: String, iv: Option<Vec<u8>> },
+}
+
+impl Encryption {
+    fn parse(value: &str) -> Result<Self, String> {
+        let attributes = parse_attributes(value);
+        let attribute = |name| find_attribute(&attributes, name);
+        match attribute("METHOD") {
+            Some("NONE") => Ok(Self::None),
+            Some("AES-128") => {
+                let uri = attribute("URI").ok_or("stream key has no uri")?;
+                let iv = match attribute("IV") {
+                    Some(iv) => {
+                        let iv = iv.trim_start_matches("0x").trim_start_matches("0X");
+                        let iv = hex::decode(iv).map_err(|err| format!("invalid iv, {}", err))?;
+                        if iv.len() > 16 {
+                            return Err("iv is longer than 128 bits".into());
+                        }
+                        // a shorter hexadecimal number is still a 128 bit iv
+                        let mut padded = vec![0; 16 - iv.len()];
+                        padded.extend(iv);
+                        Some(padded)
+                    }
+                    None => None,
+                };
+                Ok(Self::Aes128 {
+                    uri: uri.to_string(),
+                    iv,
+                })
+            }
+            Some(method) => Err(format!(
+                "unsupported encryption method {}, only AES-128 and unencrypted streams can be downloaded",
+                method
+            )),
+            None => Err("stream key has no method".into()),
+        }
+    }
+}
+
+/// `NAME=value` pairs of a tag, values may be quoted and contain commas
+pub fn parse_attributes(value: &str) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    let mut rest = value.trim();
+    while let Some((name, after)) = rest.split_once('=') {
+        let (value, after) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"').unwrap_or(quoted.len());
+                (&quoted[..end], quoted.get(end + 1..).unwrap_or(""))
+            }
+            None => after.split_once(',').unwrap_or((after, "")),
+        };
+        attributes.push((name.trim().to_string(), value.to_string()));
+        rest = after.trim_start_matches(',').trim_start();
+    }
+    attributes
+}
+
+fn find_attribute<'a>(attributes: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    attributes
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.as_str())
+}
+
+/// variant stream of a master playlist, with the rendition groups it uses
+#[derive(Clone, Debug, PartialEq)]
+pub struct Variant {
+    pub uri: String,
+    /// peak bits per second, from `BANDWIDTH`
+    pub bandwidth: Option<u64>,
+    pub audio: Option<String>,
+    pub subtitles: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RenditionKind {
+    Audio,
+    Subtitles,
+}
+
+/// alternative audio or subtitles of a variant, from `#EXT-X-MEDIA`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rendition {
+    pub kind: RenditionKind,
+    pub group_id: String,
+    pub language: Option<String>,
+    pub name: String,
+    /// renditions without one are already inside the variant stream
+    pub uri: Option<String>,
+    pub is_default: bool,
+}
+
+impl Rendition {
+    /// language when there is one, otherwise the name
+    pub fn label(&self) -> &str {
+        self.language.as_deref().unwrap_or(&self.name)
+    }
+}
+
+/// variants in the order they are listed, with every rendition of the playlist
+pub fn parse_master(playlist_text: &str) -> (Vec<Variant>, Vec<Rendition>) {
+    let mut variants = Vec::new();
+    let mut renditions = Vec::new();
+    let mut pending_variant: Option<Vec<(String, String)>> = None;
+
+    for line in playlist_text.lines() {
+        let line = line.trim();
+        if let Some(value) = line.strip_prefix("#EXT-X-STREAM-INF:") {
+            pending_variant = Some(parse_attributes(value));
+        } else if let Some(value) = line.strip_prefix("#EXT-X-MEDIA:") {
+            let attributes = parse_attributes(value);
+            let attribute = |name| find_attribute(&attributes, name).map(str::to_string);
+            let kind = match attribute("TYPE").as_deref() {
+                Some("AUDIO") => RenditionKind::Audio,
+                Some("SUBTITLES") => RenditionKind::Subtitles,
+                _ => continue,
+            };
+            renditions.push(Rendition {
+                kind,
+                group_id: attribute("GROUP-ID").unwrap_or_default(),
+                language: attribute("LANGUAGE"),
+                name: attribute("NAME").unwrap_or_default(),
+                uri: attribute("URI"),
+                is_default: attribute("DEFAULT").as_deref() == Some("YES"),
+            });
+        } else if !line.is_empty() && !line.starts_with('#') {
+            if let Some(attributes) = pending_variant.take() {
+                let attribute = |name| find_attribute(&attributes, name).map(str::to_string);
+                variants.push(Variant {
+                    uri: line.to_string(),
+                    bandwidth: attribute("BANDWIDTH").and_then(|x| x.parse().ok()),
+                    audio: attribute("AUDIO"),
+                    subtitles: attribute("SUBTITLES"),
+                });
+            }
+        }
+    }
+
+    (variants, renditions)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MediaSegment {
+    pub link: String,
+    /// seconds of media, from `#EXTINF`
+    pub duration: f64,
+    pub byte_range: Option<ByteRange>,
+    pub encryption: Encryption,
+    /// media sequence number, the iv of segments whose key has none
+    pub sequence: u64,
+}
+
+impl MediaSegment {
+    pub fn iv(&self) -> Option<Vec<u8>> {
+        match &self.encryption {
+            Encryption::None => None,
+            Encryption::Aes128 { iv: Some(iv), .. } => Some(iv.clone()),
+            Encryption::Aes128 { iv: None, .. } => {
+                Some((self.sequence as u128).to_be_bytes().to_vec())
+            }
+        }
+    }
+}
+
+/// playlists without `#EXT-X-KEY` are unencrypted
+pub fn parse_segments(playlist_text: &str) -> Result<Vec<MediaSegment>, String> {
+    let mut segments = Vec::new();
+    let mut duration = 0.0;
+    let mut encryption = Encryption::None;
+    let mut sequence = 0;
+    let mut pending_range: Option<(u64, Option<u64>)> = None;
+    // a range without offset continues where the previous range of the same link ended
+    let mut previous_end: Option<(String, u64)> = None;
+
+    for line in playlist_text.lines() {
+        let line = line.trim();
+        if let Some(value) = line.strip_prefix("#EXTINF:") {
+            duration = value
+                .split(',')
+                .next()
+                .and_then(|x| x.trim().parse().ok())
+                .unwrap_or(0.0);
+        } else if let Some(value) = line.strip_prefix("#EXT-X-MEDIA-SEQUENCE:") {
+            sequence = value.trim().parse().unwrap_or(0);
+        } else if let Some(value) = line.strip_prefix("#EXT-X-KEY:") {
+            encryption = Encryption::parse(value)?;
+        } else if let Some(value) = line.strip_prefix("#EXT-X-BYTERANGE:") {
+            let mut parts = value.split('@');
+            let length = parts.next().and_then(|x| x.trim().parse().ok());
+            let offset = parts.next().and_then(|x| x.trim().parse().ok());
+            pending_range = length.map(|length| (length, offset));
+        } else if line.starts_with("https://") {
+            let byte_range = pending_range.take().map(|(length, offset)| {
+                let offset = offset.unwrap_or(match &previous_end {
+                    Some((link, end)) if link == line => *end,
+                    _ => 0,
+                });
+                ByteRange { length, offset }
+            });
+            if let Some(range) = byte_range {
+                previous_end = Some((line.to_string(), range.offset + range.length));
+            }
+            segments.push(MediaSegment {
+                link: line.to_string(),
+                duration,
+                byte_range,
+                encryption: encryption.clone(),
+                sequence,
+            });
+            duration = 0.0;
+            sequence += 1;
+        }
+    }
+
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod playlist_tests {
+    use super::*;
+
+    #[test]
+    fn segments_with_durations_and_ranges() {
+        let playlist = "#EXTM3U\n\
+            #EXT-X-KEY:METHOD=AES-128,URI=\"https://key.example/k\",IV=0x00\n\
+            #EXTINF:10.0,\n\
+            https://cdn.example/a.ts\n\
+            #EXTINF:4.5,\n\
+            #EXT-X-BYTERANGE:1000@200\n\
+            https://cdn.example/b.ts\n\
+            #EXTINF:4.5,\n\
+            #EXT-X-BYTERANGE:500\n\
+            https://cdn.example/b.ts\n\
+            #EXT-X-ENDLIST\n";
+
+        let segments = parse_segments(playlist).unwrap();
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments[0].duration, 10.0);
+        assert_eq!(segments[0].byte_range, None);
+        assert_eq!(
+            segments[1].byte_range,
+            Some(ByteRange {
+                length: 1000,
+                offset: 200
+            })
+        );
+        assert_eq!(
+            segments[2].byte_range,
+            Some(ByteRange {
+                length: 500,
+                offset: 1200
+            })
+        );
+        assert_eq!(
+            segments[2].byte_range.unwrap().header_value(),
+            "bytes=1200-1699"
+        );
+        assert_eq!(segments[0].iv(), Some(vec![0; 16]));
+    }
+
+    #[test]
+    fn encryption_methods() {
+        let playlist = "#EXTM3U\n\
+            #EXT-X-MEDIA-SEQUENCE:7\n\
+            #EXTINF:10.0,\n\
+            https://cdn.example/a.ts\n\
+            #EXT-X-KEY:METHOD=AES-128,URI=\"https://key.example/k?a=1,b=2\"\n\
+            #EXTINF:10.0,\n\
+            https://cdn.example/b.ts\n\
+            #EXT-X-KEY:METHOD=NONE\n\
+            #EXTINF:10.0,\n\
+            https://cdn.example/c.ts\n";
+
+        let segments = parse_segments(playlist).unwrap();
+        assert_eq!(segments[0].iv(), None);
+        assert_eq!(
+            segments[1].encryption,
+            Encryption::Aes128 {
+                uri: "https://key.example/k?a=1,b=2".into(),
+                iv: None
+            }
+        );
+        // the media sequence number as a big endian 128 bit integer
+        let mut iv = vec![0; 16];
+        iv[15] = 8;
+        assert_eq!(segments[1].iv(), Some(iv));
+        assert_eq!(segments[2].encryption, Encryption::None);
+
+        let sample_aes = "#EXT-X-KEY:METHOD=SAMPLE-AES,URI=\"skd://k\"\n";
+        assert!(parse_segments(sample_aes)
+            .unwrap_err()
+            .contains("SAMPLE-AES"));
+    }
+
+    #[test]
+    fn resume_ranges() {
+        let range = ByteRange {
+            length: 500,
+            offset: 1200,
+        };
+        assert_eq!(range_header(Some(range), 0).unwrap(), "bytes=1200-1699");
+        assert_eq!(range_header(Some(range), 100).unwrap(), "bytes=1300-1699");
+        assert_eq!(range_header(None, 100).unwrap(), "bytes=100-");
+        assert_eq!(range_header(None, 0), None);
+
+        // the whole resource in chunks of 1000 bytes, in reply to the range
+        let resource: Vec<u8> = (0..3000).map(|x| x as u8).collect();
+        let sliced: Vec<u8> = resource
+            .chunks(1000)
+            .enumerate()
+            .flat_map(|(index, chunk)| slice_to_range(chunk, index as u64 * 1000, range).to_vec())
+            .collect();
+        assert_eq!(sliced, &resource[1200..1700]);
+    }
+
+    #[test]
+    fn master_with_renditions() {
+        let playlist = "#EXTM3U\n\
+            #EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"aac\",LANGUAGE=\"fa\",NAME=\"Persian\",DEFAULT=YES\n\
+            #EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"aac\",LANGUAGE=\"en\",NAME=\"English\",URI=\"https://cdn.example/en.m3u8\"\n\
+            #EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID=\"subs\",NAME=\"Notes, slides\",URI=\"https://cdn.example/s.m3u8\"\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=800000,CODECS=\"avc1.4d401f,mp4a.40.2\",AUDIO=\"aac\",SUBTITLES=\"subs\"\n\
+            https://cdn.example/480.m3u8\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=1400000\n\
+            https://cdn.example/720.m3u8\n";
+
+        let (variants, renditions) = parse_master(playlist);
+        assert_eq!(variants.len(), 2);
+        assert_eq!(variants[0].uri, "https://cdn.example/480.m3u8");
+        assert_eq!(variants[0].audio.as_deref(), Some("aac"));
+        assert_eq!(variants[0].subtitles.as_deref(), Some("subs"));
+        assert_eq!(variants[1].audio, None);
+        assert_eq!(variants[1].bandwidth, Some(1400000));
+
+        assert_eq!(renditions.len(), 3);
+        assert!(renditions[0].is_default);
+        assert_eq!(renditions[0].uri, None);
+        assert_eq!(renditions[1].label(), "en");
+        assert_eq!(renditions[2].kind, RenditionKind::Subtitles);
+        assert_eq!(renditions[2].label(), "Notes, slides");
+    }
+}
@@ -0,0 +1,78 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// counters shared between a running download and whoever watches it,
+/// the kdam bar of the download is fed from the same numbers
+#[derive(Default)]
+pub struct Progress {
+    downloaded: AtomicUsize,
+    /// part of `downloaded` that an earlier run left on disk
+    resumed: AtomicUsize,
+    total: AtomicUsize,
+    /// media duration in milliseconds
+    media_downloaded: AtomicU64,
+    media_total: AtomicU64,
+    paused: AtomicBool,
+}
+
+impl Progress {
+    pub fn downloaded(&self) -> usize {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    /// bytes this run actually transferred, resumed parts took no time
+    pub fn transferred(&self) -> usize {
+        self.downloaded().saturating_sub(self.resumed.load(Ordering::Relaxed))
+    }
+
+    pub fn total(&self) -> usize {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// seconds of media already downloaded
+    pub fn media_downloaded(&self) -> f64 {
+        self.media_downloaded.load(Ordering::Relaxed) as f64 / 1000.0
+    }
+
+    pub fn media_total(&self) -> f64 {
+        self.media_total.load(Ordering::Relaxed) as f64 / 1000.0
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Relaxed);
+    }
+
+    pub(super) fn start(&self, total: usize, media_total: f64) {
+        self.total.store(total, Ordering::Relaxed);
+        self.downloaded.store(0, Ordering::Relaxed);
+        self.resumed.store(0, Ordering::Relaxed);
+        self.media_total
+            .store((media_total * 1000.0) as u64, Ordering::Relaxed);
+        self.media_downloaded.store(0, Ordering::Relaxed);
+    }
+
+    pub(super) fn advance(&self, bytes: usize, media: f64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+        self.media_downloaded
+            .fetch_add((media * 1000.0) as u64, Ordering::Relaxed);
+    }
+
+    pub(super) fn resumed(&self, bytes: usize) {
+        self.resumed.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// parts already in flight keep going, new ones wait here until resumed
+    pub(super) async fn wait_while_paused(&self) {
+        while self.is_paused() {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use super::playlist::Variant;
+use super::VideoQuality;
+
+/// how the quality of a video is chosen when the user is not prompted
+#[derive(Clone, Debug, PartialEq)]
+pub enum QualityPolicy {
+    /// exact resolution, e.g. `720` matches the `720p` entry
+    Exact(String),
+    Highest,
+    Lowest,
+}
+
+impl From<&str> for QualityPolicy {
+    fn from(value: &str) -> Self {
+        match value.to_lowercase().as_str() {
+            "best" | "highest" => Self::Highest,
+            "worst" | "lowest" => Self::Lowest,
+            other => Self::Exact(other.trim_end_matches('p').to_string()),
+        }
+    }
+}
+
+impl QualityPolicy {
+    /// returns index of the matching entry in the embed `download` list
+    pub fn select(&self, qualities: &[VideoQuality]) -> Result<usize, String> {
+        let resolution = |quality: &VideoQuality| {
+            quality.name.trim_end_matches('p').parse::<u32>().unwrap_or(0)
+        };
+        let found = match self {
+            Self::Exact(name) => {
+                let desired_quality = format!("{}p", name);
+                return qualities
+                    .iter()
+                    .position(|x| x.name == desired_quality)
+                    .ok_or(format!(
+                        "Specified quality {} is unavalable in video",
+                        &desired_quality
+                    ));
+            }
+            Self::Highest => qualities
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, quality)| resolution(quality)),
+            Self::Lowest => qualities
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, quality)| resolution(quality)),
+        };
+        found
+            .map(|(index, _)| index)
+            .ok_or("Video has no downloadable quality".to_string())
+    }
+}
+
+/// index of the smallest variant for audio only downloads, the audio is the
+/// same in every variant so the smallest one wastes the least. `BANDWIDTH`
+/// decides, the sizes in the embed `download` list when no variant has one
+pub fn smallest_variant(variants: &[Variant], qualities: &[VideoQuality]) -> Option<usize> {
+    let lowest = variants
+        .iter()
+        .enumerate()
+        .filter_map(|(index, variant)| Some((index, variant.bandwidth?)))
+        .min_by_key(|(_, bandwidth)| *bandwidth)
+        .map(|(index, _)| index)
+        .or_else(|| {
+            qualities
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, quality)| quality.size.parse::<u64>().unwrap_or(u64::MAX))
+                .map(|(index, _)| index)
+        })?;
+    (lowest < variants.len()).then_some(lowest)
+}
+
+#[cfg(test)]
+mod quality_tests {
+    use super::*;
+
+    fn variant(bandwidth: Option<u64>) -> Variant {
+        Variant {
+            uri: "https://cdn.example/video.m3u8".into(),
+            bandwidth,
+            audio: None,
+            subtitles: None,
+        }
+    }
+
+    fn quality(name: &str, size: &str) -> VideoQuality {
+        VideoQuality {
+            name: name.into(),
+            size: size.into(),
+        }
+    }
+
+    #[test]
+    fn smallest_variants() {
+        let qualities = [
+            quality("720p", "9000"),
+            quality("360p", "3000"),
+            quality("480p", "unknown"),
+        ];
+        let variants = [
+            variant(Some(2_800_000)),
+            variant(None),
+            variant(Some(1_400_000)),
+        ];
+        assert_eq!(Some(2), smallest_variant(&variants, &qualities));
+
+        // no bandwidth at all, the embed sizes decide
+        let variants = [variant(None), variant(None), variant(None)];
+        assert_eq!(Some(1), smallest_variant(&variants, &qualities));
+
+        // a size for a variant the playlist does not have
+        assert_eq!(None, smallest_variant(&variants[..1], &qualities));
+        assert_eq!(None, smallest_variant(&[], &[]));
+    }
+}
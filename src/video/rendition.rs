@@ -0,0 +1,358 @@
+use super::playlist::{Rendition, RenditionKind, Variant};
+use std::sync::OnceLock;
+
+/// renditions picked with `--audio-lang`, `--subtitle-lang` and `--subtitle-sidecar`
+static CHOICE: OnceLock<RenditionChoice> = OnceLock::new();
+
+const MPEGTS_CLOCK: f64 = 90_000.0;
+/// mpeg-ts timestamps are 33 bits and wrap around about every 26 hours
+const MPEGTS_WRAP: u64 = 1 << 33;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Languages {
+    /// the rendition marked `DEFAULT=YES`, or the first one
+    Default,
+    All,
+    None,
+    /// languages or names, in any case
+    Only(Vec<String>),
+}
+
+#[derive(Clone, Debug)]
+pub struct RenditionChoice {
+    pub audio: Languages,
+    pub subtitles: Languages,
+    /// keeps subtitles as `.vtt` files next to the video instead of muxing them
+    pub is_sidecar: bool,
+}
+
+impl Default for RenditionChoice {
+    fn default() -> Self {
+        Self {
+            audio: Languages::Default,
+            subtitles: Languages::None,
+            is_sidecar: false,
+        }
+    }
+}
+
+pub fn set_renditions(choice: RenditionChoice) {
+    let _ = CHOICE.set(choice);
+}
+
+/// `all`, `none`, `default` or a comma separated list like `fa,en`
+pub fn parse_languages(input: &str) -> Result<Languages, String> {
+    match input.trim().to_lowercase().as_str() {
+        "all" => Ok(Languages::All),
+        "none" => Ok(Languages::None),
+        "default" => Ok(Languages::Default),
+        list => {
+            let languages: Vec<String> = list
+                .split(',')
+                .map(|x| x.trim().to_string())
+                .filter(|x| !x.is_empty())
+                .collect();
+            if languages.is_empty() {
+                return Err("no language given".into());
+            }
+            Ok(Languages::Only(languages))
+        }
+    }
+}
+
+pub fn choice() -> RenditionChoice {
+    CHOICE.get().cloned().unwrap_or_default()
+}
+
+/// renditions of the variant that have to be downloaded on their own, ones
+/// without an uri are part of the variant stream already
+pub fn select(
+    choice: &RenditionChoice,
+    variant: &Variant,
+    renditions: &[Rendition],
+) -> Vec<Rendition> {
+    let groups = [
+        (RenditionKind::Audio, &variant.audio, &choice.audio),
+        (
+            RenditionKind::Subtitles,
+            &variant.subtitles,
+            &choice.subtitles,
+        ),
+    ];
+    let mut selected = Vec::new();
+    for (kind, group, languages) in groups {
+        let Some(group) = group else {
+            continue;
+        };
+        let group: Vec<&Rendition> = renditions
+            .iter()
+            .filter(|x| x.kind == kind && &x.group_id == group)
+            .collect();
+        let picked: Vec<&Rendition> = match languages {
+            Languages::None => Vec::new(),
+            Languages::All => group,
+            Languages::Default => group
+                .iter()
+                .find(|x| x.is_default)
+                .or(group.first())
+                .into_iter()
+                .copied()
+                .collect(),
+            Languages::Only(wanted) => group
+                .into_iter()
+                .filter(|x| {
+                    wanted.iter().any(|wanted| {
+                        x.language
+                            .as_deref()
+                            .is_some_and(|language| language.eq_ignore_ascii_case(wanted))
+                            || x.name.eq_ignore_ascii_case(wanted)
+                    })
+                })
+                .collect(),
+        };
+        selected.extend(picked.into_iter().filter(|x| x.uri.is_some()).cloned());
+    }
+    selected
+}
+
+/// joins the WebVTT files of the segments, only the header of the first one
+/// is kept. Cues are moved to media time with the `X-TIMESTAMP-MAP` of their
+/// segment, `mpegts_start` being the timestamp of the video at media time zero;
+/// segments without a map, or without the timestamp of the video, are taken
+/// as being in media time already
+pub fn merge_webvtt(segments: &[String], mpegts_start: Option<u64>) -> String {
+    let mut merged = String::new();
+    for (index, segment) in segments.iter().enumerate() {
+        let segment = segment.replace("\r\n", "\n");
+        let segment = segment.trim_start_matches('\u{feff}');
+        // the header lasts until the first blank line
+        let (header, cues) = segment.split_once("\n\n").unwrap_or((segment, ""));
+        if index == 0 {
+            // the merged cues are in media time, a map would move them again
+            let header: Vec<&str> = header
+                .lines()
+                .filter(|line| !line.starts_with("X-TIMESTAMP-MAP="))
+                .collect();
+            merged.push_str(&header.join("\n"));
+            merged.push_str("\n\n");
+        }
+        let offset = match (timestamp_map(header), mpegts_start) {
+            (Some((mpegts, local)), Some(start)) => mpegts_seconds(mpegts, start) - local,
+            _ => 0.0,
+        };
+        merged.push_str(&shift_cues(cues.trim(), -offset, f64::INFINITY));
+    }
+    merged
+}
+
+/// `MPEGTS` and `LOCAL` of the `X-TIMESTAMP-MAP` in a header, the cue at
+/// `LOCAL` seconds plays at the `MPEGTS` timestamp
+fn timestamp_map(header: &str) -> Option<(u64, f64)> {
+    let map = header
+        .lines()
+        .find_map(|line| line.strip_prefix("X-TIMESTAMP-MAP="))?;
+    let mut mpegts = None;
+    let mut local = 0.0;
+    for pair in map.split(',') {
+        match pair.trim().split_once(':') {
+            Some(("MPEGTS", value)) => mpegts = value.parse().ok(),
+            Some(("LOCAL", value)) => local = parse_cue_time(value)?,
+            _ => (),
+        }
+    }
+    Some((mpegts?, local))
+}
+
+/// seconds from `start` to `mpegts`, negative for a timestamp before it
+fn mpegts_seconds(mpegts: u64, start: u64) -> f64 {
+    let ticks = (mpegts % MPEGTS_WRAP + MPEGTS_WRAP - start % MPEGTS_WRAP) % MPEGTS_WRAP;
+    let ticks = if ticks > MPEGTS_WRAP / 2 {
+        ticks as i64 - MPEGTS_WRAP as i64
+    } else {
+        ticks as i64
+    };
+    ticks as f64 / MPEGTS_CLOCK
+}
+
+/// timestamp at media time zero of a stream whose first timestamp `pts` is
+/// `media_time` seconds into the playlist
+pub fn mpegts_start(pts: u64, media_time: f64) -> u64 {
+    let ticks = (media_time * MPEGTS_CLOCK) as u64 % MPEGTS_WRAP;
+    (pts % MPEGTS_WRAP + MPEGTS_WRAP - ticks) % MPEGTS_WRAP
+}
+
+/// moves the cues `offset` seconds earlier for a video cut out of a longer
+/// one, cues outside of the `length` seconds left are dropped
+pub fn shift_webvtt(subtitles: &str, offset: f64, length: f64) -> String {
+    let (header, cues) = subtitles.split_once("\n\n").unwrap_or((subtitles, ""));
+    format!("{}\n\n{}", header.trim(), shift_cues(cues, offset, length))
+}
+
+/// cue blocks of a WebVTT file without its header, blocks that are not cues
+/// like `NOTE` and `STYLE` are kept as they are
+fn shift_cues(cues: &str, offset: f64, length: f64) -> String {
+    let mut shifted = String::new();
+    for block in cues.split("\n\n") {
+        let timing = block.lines().position(|line| line.contains("-->"));
+        let Some(timing) = timing else {
+            if !block.trim().is_empty() {
+                shifted.push_str(block.trim());
+                shifted.push_str("\n\n");
+            }
+            continue;
+        };
+        let lines: Vec<&str> = block.lines().collect();
+        let (times, settings) = lines[timing].split_once("-->").unwrap_or_default();
+        let mut settings = settings.trim().splitn(2, ' ');
+        let start = parse_cue_time(times.trim());
+        let end = settings.next().and_then(parse_cue_time);
+        let (Some(start), Some(end)) = (start, end) else {
+            continue;
+        };
+        let (start, end) = (start - offset, end - offset);
+        if end <= 0.0 || start >= length {
+            continue;
+        }
+        let mut timing_line = format!(
+            "{} --> {}",
+            format_cue_time(start.max(0.0)),
+            format_cue_time(end.min(length))
+        );
+        if let Some(settings) = settings.next() {
+            timing_line.push(' ');
+            timing_line.push_str(settings);
+        }
+        let mut lines = lines;
+        lines[timing] = &timing_line;
+        shifted.push_str(lines.join("\n").trim());
+        shifted.push_str("\n\n");
+    }
+    shifted
+}
+
+/// `HH:MM:SS.mmm` or `MM:SS.mmm` as seconds
+fn parse_cue_time(input: &str) -> Option<f64> {
+    let mut seconds = 0.0;
+    for part in input.split(':') {
+        seconds = seconds * 60.0 + part.parse::<f64>().ok()?;
+    }
+    Some(seconds)
+}
+
+fn format_cue_time(seconds: f64) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+#[cfg(test)]
+mod rendition_tests {
+    use super::*;
+
+    fn rendition(kind: RenditionKind, language: &str, uri: bool, is_default: bool) -> Rendition {
+        Rendition {
+            kind,
+            group_id: "group".into(),
+            language: Some(language.into()),
+            name: language.to_uppercase(),
+            uri: uri.then(|| format!("https://cdn.example/{}.m3u8", language)),
+            is_default,
+        }
+    }
+
+    #[test]
+    fn selection() {
+        let variant = Variant {
+            uri: "https://cdn.example/480.m3u8".into(),
+            bandwidth: None,
+            audio: Some("group".into()),
+            subtitles: Some("group".into()),
+        };
+        let renditions = [
+            rendition(RenditionKind::Audio, "fa", false, true),
+            rendition(RenditionKind::Audio, "en", true, false),
+            rendition(RenditionKind::Subtitles, "fa", true, false),
+            rendition(RenditionKind::Subtitles, "en", true, false),
+        ];
+
+        // the default audio is inside the variant, subtitles are opt in
+        assert!(select(&RenditionChoice::default(), &variant, &renditions).is_empty());
+
+        let choice = RenditionChoice {
+            audio: parse_languages("EN").unwrap(),
+            subtitles: parse_languages("all").unwrap(),
+            is_sidecar: false,
+        };
+        let selected = select(&choice, &variant, &renditions);
+        let labels: Vec<_> = selected.iter().map(|x| (x.kind, x.label())).collect();
+        assert_eq!(
+            labels,
+            [
+                (RenditionKind::Audio, "en"),
+                (RenditionKind::Subtitles, "fa"),
+                (RenditionKind::Subtitles, "en")
+            ]
+        );
+    }
+
+    #[test]
+    fn webvtt_segments() {
+        // the same map in every segment, cues are in media time already
+        let segments = [
+            "WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:900000,LOCAL:00:00:00.000\n\n00:00:01.000 --> 00:00:02.000\nSalam\n".to_string(),
+            "WEBVTT\r\nX-TIMESTAMP-MAP=MPEGTS:900000,LOCAL:00:00:00.000\r\n\r\n".to_string(),
+            "WEBVTT\n\n00:00:11.000 --> 00:00:12.000\nHello\n".to_string(),
+        ];
+        let merged = "WEBVTT\n\n00:00:01.000 --> 00:00:02.000\nSalam\n\n\
+            00:00:11.000 --> 00:00:12.000\nHello\n\n";
+        assert_eq!(merge_webvtt(&segments, Some(900_000)), merged);
+        assert_eq!(merge_webvtt(&segments, None), merged);
+    }
+
+    #[test]
+    fn webvtt_timestamp_maps() {
+        // every segment counts from its own start, which the map places on the mpeg-ts clock
+        let segments = [
+            "WEBVTT\nX-TIMESTAMP-MAP=LOCAL:00:00:00.000,MPEGTS:900000\n\n00:00:01.000 --> 00:00:02.000\nSalam\n".to_string(),
+            "WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:1440000,LOCAL:00:00:00.000\n\nNOTE translated\n\n00:00:01.000 --> 00:00:02.500\nHello\n".to_string(),
+            "WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:1980000,LOCAL:00:00:10.000\n\n00:00:10.500 --> 00:00:11.000\nBye\n".to_string(),
+        ];
+        let start = mpegts_start(900_000, 0.0);
+        assert_eq!(
+            merge_webvtt(&segments, Some(start)),
+            "WEBVTT\n\n00:00:01.000 --> 00:00:02.000\nSalam\n\n\
+            NOTE translated\n\n00:00:07.000 --> 00:00:08.500\nHello\n\n\
+            00:00:12.500 --> 00:00:13.000\nBye\n\n"
+        );
+
+        // a section that starts 6 seconds into the playlist
+        let start = mpegts_start(1_440_000, 6.0);
+        assert_eq!(900_000, start);
+        assert_eq!(
+            merge_webvtt(&segments[1..], Some(start)),
+            "WEBVTT\n\nNOTE translated\n\n00:00:07.000 --> 00:00:08.500\nHello\n\n\
+            00:00:12.500 --> 00:00:13.000\nBye\n\n"
+        );
+
+        // timestamps wrap around after 2^33 ticks
+        assert_eq!(1.0, mpegts_seconds(45_000, MPEGTS_WRAP - 45_000));
+        assert_eq!(-1.0, mpegts_seconds(MPEGTS_WRAP - 45_000, 45_000));
+    }
+
+    #[test]
+    fn webvtt_shift() {
+        let subtitles = "WEBVTT\n\n\
+            00:00:05.000 --> 00:00:08.000\nBefore\n\n\
+            1\n00:09.500 --> 00:12.000 align:start\nAcross\n\n\
+            00:00:40.000 --> 00:00:41.000\nAfter\n\n";
+        assert_eq!(
+            shift_webvtt(subtitles, 10.0, 20.0),
+            "WEBVTT\n\n1\n00:00:00.000 --> 00:00:02.000 align:start\nAcross\n\n"
+        );
+    }
+}
@@ -0,0 +1,112 @@
+use super::playlist::MediaSegment;
+use std::ops::Range;
+use std::sync::OnceLock;
+
+use crate::timer;
+
+/// part of every video picked with `--section`
+static SECTION: OnceLock<Section> = OnceLock::new();
+
+/// seconds of media time
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Section {
+    pub start: f64,
+    pub end: f64,
+}
+
+impl Section {
+    /// `00-10-00 00-25-30`, colons are not allowed in windows file names
+    pub fn file_suffix(&self) -> String {
+        let clock = |seconds: f64| {
+            let seconds = seconds as u32;
+            format!(
+                "{:02}-{:02}-{:02}",
+                seconds / 3600,
+                seconds / 60 % 60,
+                seconds % 60
+            )
+        };
+        format!("{} {}", clock(self.start), clock(self.end))
+    }
+}
+
+pub fn set_section(section: Option<Section>) {
+    if let Some(section) = section {
+        let _ = SECTION.set(section);
+    }
+}
+
+pub fn section() -> Option<Section> {
+    SECTION.get().copied()
+}
+
+/// `00:10:00-00:25:30`, in the same form as the timer
+pub fn parse_section(input: &str) -> Result<Section, String> {
+    let range = timer::parse_time(input)
+        .map_err(|_| format!("'{}' is not in HH:MM:SS-HH:MM:SS form", input))?;
+    if range.end <= range.start {
+        return Err(format!("'{}' ends before it starts", input));
+    }
+    Ok(Section {
+        start: range.start as f64,
+        end: range.end as f64,
+    })
+}
+
+/// segments that overlap the section by their `#EXTINF` durations, and the
+/// media time the first of them starts at
+pub fn overlapping(segments: &[MediaSegment], section: Section) -> (Range<usize>, f64) {
+    let mut first = None;
+    let mut first_start = 0.0;
+    let mut last = 0;
+    let mut start = 0.0;
+    for (index, segment) in segments.iter().enumerate() {
+        let end = start + segment.duration;
+        if end > section.start && start < section.end {
+            if first.is_none() {
+                first = Some(index);
+                first_start = start;
+            }
+            last = index + 1;
+        }
+        start = end;
+    }
+    match first {
+        Some(first) => (first..last, first_start),
+        None => (0..0, 0.0),
+    }
+}
+
+#[cfg(test)]
+mod section_tests {
+    use super::*;
+    use crate::video::playlist::Encryption;
+
+    #[test]
+    fn overlapping_segments() {
+        let section = parse_section("00:00:15-00:00:35").unwrap();
+        assert_eq!(
+            section,
+            Section {
+                start: 15.0,
+                end: 35.0
+            }
+        );
+        assert!(parse_section("00:10:00-00:05:00").is_err());
+        assert_eq!(section.file_suffix(), "00-00-15 00-00-35");
+
+        let segments: Vec<_> = (0..6)
+            .map(|index| MediaSegment {
+                link: format!("https://cdn.example/{}.ts", index),
+                duration: 10.0,
+                byte_range: None,
+                encryption: Encryption::None,
+                sequence: index,
+            })
+            .collect();
+        assert_eq!(overlapping(&segments, section), (1..4, 10.0));
+
+        let past_the_end = parse_section("00:02:00-00:03:00").unwrap();
+        assert_eq!(overlapping(&segments, past_the_end), (0..0, 0.0));
+    }
+}
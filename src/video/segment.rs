@@ -0,0 +1,311 @@
+use super::decrypt::{SegmentDecryptor, SegmentKey};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::utils::write_atomically;
+
+const TS_PACKET_SIZE: usize = 188;
+const TS_SYNC_BYTE: u8 = 0x47;
+/// written length and iv in front of the pending ciphertext
+const STATE_HEADER_SIZE: usize = 24;
+/// the state is saved after this many bytes and when the segment is dropped
+/// unfinished, a run that is killed repeats at most this much
+const STATE_EVERY: u64 = 1 << 20;
+
+/// every MPEG-TS packet is 188 bytes long and starts with the sync byte
+pub fn is_valid_ts(bytes: &[u8]) -> bool {
+    !bytes.is_empty() && has_sync_bytes(0, bytes)
+}
+
+/// checks the packet starts inside bytes that begin at `offset` of a stream
+fn has_sync_bytes(offset: u64, bytes: &[u8]) -> bool {
+    let packet_size = TS_PACKET_SIZE as u64;
+    let first = ((packet_size - offset % packet_size) % packet_size) as usize;
+    bytes
+        .iter()
+        .skip(first)
+        .step_by(TS_PACKET_SIZE)
+        .all(|byte| *byte == TS_SYNC_BYTE)
+}
+
+/// earliest presentation timestamp of the PES packets in the bytes, in
+/// ticks of the 90kHz mpeg-ts clock
+pub fn earliest_pts(bytes: &[u8]) -> Option<u64> {
+    bytes
+        .chunks_exact(TS_PACKET_SIZE)
+        // only the first packet of a PES packet carries its header
+        .filter(|packet| packet[0] == TS_SYNC_BYTE && packet[1] & 0x40 != 0)
+        .filter_map(|packet| {
+            let payload = match packet[3] >> 4 & 0b11 {
+                0b01 => &packet[4..],
+                0b11 => packet.get(5 + packet[4] as usize..)?,
+                _ => return None,
+            };
+            // start code, stream id, length, two flag bytes, header length and the PTS
+            if payload.len() < 14 || !payload.starts_with(&[0, 0, 1]) || payload[7] & 0x80 == 0 {
+                return None;
+            }
+            let pts = &payload[9..14];
+            Some(
+                (pts[0] as u64 >> 1 & 0b111) << 30
+                    | (pts[1] as u64) << 22
+                    | (pts[2] as u64 >> 1) << 15
+                    | (pts[3] as u64) << 7
+                    | pts[4] as u64 >> 1,
+            )
+        })
+        .min()
+}
+
+/// checks a part left by a previous run against the size in the manifest,
+/// empty placeholders of corrupted parts are downloaded again
+pub fn is_complete(path: &Path, expected_size: u64, is_mpeg_ts: bool) -> bool {
+    match fs::read(path) {
+        Ok(bytes) => {
+            bytes.len() as u64 == expected_size
+                && !bytes.is_empty()
+                && (!is_mpeg_ts || is_valid_ts(&bytes))
+        }
+        Err(_) => false,
+    }
+}
+
+/// a segment that is decrypted and written while its body arrives, the
+/// decryption state is kept next to it so another attempt or another run
+/// resumes where this one stopped
+pub struct PartialSegment {
+    path: PathBuf,
+    key: Option<SegmentKey>,
+    file: fs::File,
+    decryptor: SegmentDecryptor,
+    /// decrypted bytes on disk
+    written: u64,
+    /// bytes received since the state was saved
+    unsaved: u64,
+    /// WebVTT subtitles are not checked for sync bytes
+    is_mpeg_ts: bool,
+    is_valid: bool,
+}
+
+impl PartialSegment {
+    pub fn open(path: &Path, key: Option<SegmentKey>, is_mpeg_ts: bool) -> std::io::Result<Self> {
+        let partial_path = path.with_extension("ts.part");
+        let state = fs::read(path.with_extension("ts.state"))
+            .ok()
+            .filter(|state| state.len() >= STATE_HEADER_SIZE);
+        let Some(state) = state else {
+            return Self::create(path, key, is_mpeg_ts);
+        };
+        let written = u64::from_le_bytes(state[..8].try_into().unwrap());
+        let file = fs::OpenOptions::new().append(true).open(&partial_path);
+        let file = match file {
+            // the body may have been written without the state that follows it
+            Ok(file) if file.metadata()?.len() >= written => {
+                file.set_len(written)?;
+                file
+            }
+            _ => return Self::create(path, key, is_mpeg_ts),
+        };
+        let decryptor = SegmentDecryptor::resume(
+            key.as_ref().map(|x| x.key.clone()),
+            state[8..24].to_vec(),
+            state[24..].to_vec(),
+        );
+        Ok(Self {
+            path: path.to_path_buf(),
+            key,
+            file,
+            decryptor,
+            written,
+            unsaved: 0,
+            is_mpeg_ts,
+            is_valid: true,
+        })
+    }
+
+    fn create(path: &Path, key: Option<SegmentKey>, is_mpeg_ts: bool) -> std::io::Result<Self> {
+        let file = fs::File::create(path.with_extension("ts.part"))?;
+        let _ = fs::remove_file(path.with_extension("ts.state"));
+        Ok(Self {
+            path: path.to_path_buf(),
+            decryptor: SegmentDecryptor::new(key.clone()),
+            key,
+            file,
+            written: 0,
+            unsaved: 0,
+            is_mpeg_ts,
+            is_valid: true,
+        })
+    }
+
+    /// starts over, for servers that answer a range with the whole body
+    pub fn restart(&mut self) -> std::io::Result<()> {
+        // the old state must not be saved over the new one when dropped
+        self.unsaved = 0;
+        *self = Self::create(&self.path, self.key.clone(), self.is_mpeg_ts)?;
+        Ok(())
+    }
+
+    /// encrypted bytes received so far
+    pub fn received(&self) -> u64 {
+        self.written + self.decryptor.pending().len() as u64
+    }
+
+    /// false once the decrypted body stopped looking like MPEG-TS, for a
+    /// wrong key or a broken mirror there is no point in receiving the rest
+    pub fn is_valid(&self) -> bool {
+        self.is_valid
+    }
+
+    pub fn write(&mut self, chunk: &[u8]) -> std::io::Result<()> {
+        let decrypted = self.decryptor.update(chunk);
+        self.append(&decrypted)?;
+        self.unsaved += chunk.len() as u64;
+        if self.unsaved >= STATE_EVERY {
+            self.save_state()?;
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, decrypted: &[u8]) -> std::io::Result<()> {
+        if self.is_mpeg_ts {
+            self.is_valid &= has_sync_bytes(self.written, decrypted);
+        }
+        self.file.write_all(decrypted)?;
+        self.written += decrypted.len() as u64;
+        Ok(())
+    }
+
+    fn save_state(&mut self) -> std::io::Result<()> {
+        self.unsaved = 0;
+        let mut state = self.written.to_le_bytes().to_vec();
+        state.extend_from_slice(self.decryptor.iv());
+        state.extend_from_slice(self.decryptor.pending());
+        write_atomically(&self.path.with_extension("ts.state"), &state)
+    }
+
+    /// decrypts the last block and moves the part to its final name, `None`
+    /// when the body was not a valid stream and got thrown away
+    pub fn finish(mut self) -> std::io::Result<Option<u64>> {
+        self.unsaved = 0;
+        match self.decryptor.finish() {
+            Some(decrypted) if self.is_valid => self.append(&decrypted)?,
+            _ => self.is_valid = false,
+        }
+        if !self.is_valid || self.written == 0 {
+            Self::discard(&self.path);
+            return Ok(None);
+        }
+        self.file.sync_all()?;
+        fs::rename(self.path.with_extension("ts.part"), &self.path)?;
+        let _ = fs::remove_file(self.path.with_extension("ts.state"));
+        Ok(Some(self.written))
+    }
+
+    /// removes what an unfinished download of the part left behind
+    pub fn discard(path: &Path) {
+        let _ = fs::remove_file(path.with_extension("ts.part"));
+        let _ = fs::remove_file(path.with_extension("ts.state"));
+    }
+}
+
+impl Drop for PartialSegment {
+    /// keeps what was received for the next attempt or run
+    fn drop(&mut self) {
+        if self.unsaved > 0 {
+            let _ = self.save_state();
+        }
+    }
+}
+
+#[cfg(test)]
+mod segment_tests {
+    use super::*;
+
+    #[test]
+    fn ts_validation() {
+        let mut bytes = vec![0_u8; TS_PACKET_SIZE * 3];
+        for packet in bytes.chunks_mut(TS_PACKET_SIZE) {
+            packet[0] = TS_SYNC_BYTE;
+        }
+        assert!(is_valid_ts(&bytes));
+
+        // a chunk in the middle of the stream
+        assert!(has_sync_bytes(100, &bytes[100..]));
+        assert!(!has_sync_bytes(99, &bytes[100..]));
+
+        bytes[TS_PACKET_SIZE] = 0x00;
+        assert!(!is_valid_ts(&bytes));
+        assert!(!is_valid_ts(&[]));
+    }
+
+    #[test]
+    fn presentation_timestamps() {
+        let pes = |pts: u64| {
+            let mut packet = vec![0xff_u8; TS_PACKET_SIZE];
+            packet[..4].copy_from_slice(&[TS_SYNC_BYTE, 0x41, 0x00, 0x10]);
+            packet[4..13].copy_from_slice(&[0, 0, 1, 0xe0, 0, 0, 0x80, 0x80, 5]);
+            packet[13..18].copy_from_slice(&[
+                0x21 | ((pts >> 30) as u8 & 0b111) << 1,
+                (pts >> 22) as u8,
+                (pts >> 14) as u8 | 1,
+                (pts >> 7) as u8,
+                (pts << 1) as u8 | 1,
+            ]);
+            packet
+        };
+        // a B-frame shown before the keyframe it follows
+        let mut bytes = pes(903_003);
+        bytes.extend(pes(900_000));
+        let mut continued = pes(0);
+        continued[1] = 0x01;
+        bytes.extend(continued);
+        assert_eq!(Some(900_000), earliest_pts(&bytes));
+        assert_eq!(Some((1 << 33) - 1), earliest_pts(&pes((1 << 33) - 1)));
+        assert_eq!(None, earliest_pts(&[]));
+    }
+
+    #[test]
+    fn placeholders_are_not_complete() {
+        let directory = std::env::temp_dir().join("kavimo-segment-tests");
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("Vpart-0000000000-00.ts");
+
+        write_atomically(&path, &[]).unwrap();
+        assert!(!is_complete(&path, 0, true));
+        assert!(!is_complete(&path, 0, false));
+
+        let mut bytes = vec![0_u8; TS_PACKET_SIZE];
+        bytes[0] = TS_SYNC_BYTE;
+        write_atomically(&path, &bytes).unwrap();
+        assert!(is_complete(&path, TS_PACKET_SIZE as u64, true));
+        assert!(!is_complete(&path, 1, true));
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn state_is_saved_when_dropped() {
+        let directory = std::env::temp_dir().join("kavimo-partial-tests");
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("Vpart-0000000001-00.ts");
+        let state_path = path.with_extension("ts.state");
+        let mut packet = vec![0_u8; TS_PACKET_SIZE];
+        packet[0] = TS_SYNC_BYTE;
+
+        // small chunks are not worth a state file of their own
+        let mut partial = PartialSegment::open(&path, None, true).unwrap();
+        partial.write(&packet).unwrap();
+        assert!(!state_path.exists());
+        drop(partial);
+        assert!(state_path.exists());
+
+        let mut partial = PartialSegment::open(&path, None, true).unwrap();
+        assert_eq!(TS_PACKET_SIZE as u64, partial.received());
+        partial.write(&packet).unwrap();
+        assert_eq!(Some(2 * TS_PACKET_SIZE as u64), partial.finish().unwrap());
+        assert!(!state_path.exists());
+        assert!(is_complete(&path, 2 * TS_PACKET_SIZE as u64, true));
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
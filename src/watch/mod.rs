@@ -0,0 +1,125 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, read_to_string};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+use crate::batch::download_batch;
+use crate::timer::Schedule;
+
+const DONE_DIR: &str = "done";
+const FAILED_DIR: &str = "failed";
+
+/// polls `dir` for new batch files and downloads their links, moving each
+/// processed file into `done/` or `failed/` inside the watched directory
+pub async fn watch_directory(
+    dir: &str,
+    interval: u64,
+    time_range: Option<Schedule>,
+    parallel: usize,
+    cancel: CancellationToken,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let dir = PathBuf::from(dir);
+    fs::create_dir_all(dir.join(DONE_DIR))?;
+    fs::create_dir_all(dir.join(FAILED_DIR))?;
+
+    info!("Watching '{}' for batch files", dir.display());
+
+    // a file is only picked up once its size and modification time stayed the
+    // same for a whole poll interval, so lists still being copied are not read
+    let mut last_seen: HashMap<PathBuf, (u64, SystemTime)> = HashMap::new();
+    let mut unmovable: HashSet<PathBuf> = HashSet::new();
+
+    loop {
+        let mut seen = HashMap::new();
+        let mut ready = Vec::new();
+
+        for entry in fs::read_dir(&dir)?.flatten() {
+            let path = entry.path();
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            let is_hidden = entry.file_name().to_string_lossy().starts_with('.');
+            if !metadata.is_file() || is_hidden || unmovable.contains(&path) {
+                continue;
+            }
+            let stamp = (metadata.len(), metadata.modified()?);
+            if last_seen.get(&path) == Some(&stamp) {
+                ready.push(path);
+            } else {
+                seen.insert(path, stamp);
+            }
+        }
+        last_seen = seen;
+
+        ready.sort();
+        for path in ready {
+            if cancel.is_cancelled() {
+                break;
+            }
+            if let Err(err) = process_batch_file(&dir, &path, &time_range, parallel, &cancel).await {
+                error!(
+                    "Cannot move '{}' out of the watched directory due {}",
+                    path.display(),
+                    err
+                );
+                unmovable.insert(path);
+            }
+        }
+
+        tokio::select! {
+            _ = cancel.cancelled() => return Ok(()),
+            _ = tokio::time::sleep(Duration::from_secs(interval)) => (),
+        }
+    }
+}
+
+async fn process_batch_file(
+    dir: &Path,
+    path: &Path,
+    time_range: &Option<Schedule>,
+    parallel: usize,
+    cancel: &CancellationToken,
+) -> std::io::Result<()> {
+    info!("Picked up batch file '{}'", path.display());
+
+    let target = match read_to_string(path) {
+        Ok(file_content) => {
+            let report = download_batch(&file_content, time_range, parallel, cancel).await;
+            if report.cancelled || cancel.is_cancelled() {
+                // left in place so the next run picks the file up again
+                return Ok(());
+            }
+            info!(
+                "Finished '{}', downloaded: {}, failed: {}, invalid lines: {}",
+                path.display(),
+                report.succeeded,
+                report.failed,
+                report.invalid
+            );
+            if report.is_clean() {
+                DONE_DIR
+            } else {
+                FAILED_DIR
+            }
+        }
+        Err(err) => {
+            error!(
+                "Cannot open input file: '{}' due {}",
+                path.display(),
+                err
+            );
+            FAILED_DIR
+        }
+    };
+
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let mut destination = dir.join(target).join(file_name.as_ref());
+    if destination.exists() {
+        let stamp = chrono::Local::now().format("%Y%m%d-%H%M%S");
+        destination = dir.join(target).join(format!("{}-{}", stamp, file_name));
+    }
+    fs::rename(path, destination)
+}